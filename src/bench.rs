@@ -2,14 +2,20 @@ extern crate crossbeam;
 extern crate syncbox;
 extern crate test;
 
-use std::cell::RefCell;
+use std::cell::{RefCell, UnsafeCell};
+use std::collections::HashMap;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, BuildHasherDefault};
 use std::sync::Mutex;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering::Relaxed;
+use std::thread;
 
 use self::crossbeam::sync::{MsQueue, SegQueue, TreiberStack};
 use self::syncbox::ArrayQueue;
 use self::test::{Bencher, black_box};
 
-use {CreateFn, Pool};
+use {CreateFn, IdHasher, Pool, thread_id};
 
 #[derive(Debug)]
 struct Dummy(usize);
@@ -28,10 +34,7 @@ fn dummy() -> CreateFn<Box<Dummy>> {
 fn mutex_lock_unlock(b: &mut Bencher) {
     let lock = Mutex::new(());
     b.iter(|| {
-        black_box({
-            let lock = lock.lock().unwrap();
-            drop(lock);
-        })
+        drop(black_box(lock.lock().unwrap()));
     });
 }
 
@@ -40,10 +43,178 @@ fn mempool_get_put_tls(b: &mut Bencher) {
     let pool = Pool::new(dummy());
     let _ = pool.get();
     b.iter(|| {
-        black_box({
-            let data = pool.get();
-            drop(data);
-        })
+        black_box(pool.get());
+    });
+}
+
+#[bench]
+fn mempool_get_owner_unchecked(b: &mut Bencher) {
+    let pool = Pool::new(dummy());
+    let _ = pool.get();
+    assert!(pool.is_owner());
+    b.iter(|| black_box(unsafe { pool.get_owner_unchecked() }));
+}
+
+#[bench]
+fn mempool_get_mut_put_tls(b: &mut Bencher) {
+    let pool = Pool::new(dummy());
+    let _ = pool.get();
+    b.iter(|| black_box(unsafe { pool.get_mut() }));
+}
+
+// The non-owner benchmarks run on another thread, after the main thread has
+// become the owner.
+#[bench]
+fn mempool_get_non_owner(b: &mut Bencher) {
+    let pool = Pool::new(dummy());
+    let _ = pool.get();
+    thread::scope(|s| {
+        s.spawn(|| b.iter(|| black_box(pool.get())));
+    });
+}
+
+#[bench]
+fn mempool_get_mut_non_owner(b: &mut Bencher) {
+    let pool = Pool::new(dummy());
+    let _ = pool.get();
+    thread::scope(|s| {
+        s.spawn(|| b.iter(|| black_box(unsafe { pool.get_mut() })));
+    });
+}
+
+#[bench]
+fn mempool_create_boxed(b: &mut Bencher) {
+    b.iter(|| {
+        let pool = Pool::new(Box::new(|| Dummy(0)));
+        black_box(pool.get().0)
+    });
+}
+
+#[bench]
+fn mempool_create_fn(b: &mut Bencher) {
+    b.iter(|| {
+        let pool = Pool::with_fn(|| Dummy(0));
+        black_box(pool.get().0)
+    });
+}
+
+// The map lookup benchmarks compare the hasher used for thread ids on the slow
+// path with std's default SipHash.
+const LOOKUP_IDS: usize = 64;
+
+fn map_lookup<S: BuildHasher + Default>(b: &mut Bencher) {
+    let mut map: HashMap<usize, usize, S> = HashMap::default();
+    for id in 1..=LOOKUP_IDS {
+        map.insert(id, id);
+    }
+    b.iter(|| {
+        for id in 1..=LOOKUP_IDS {
+            black_box(map.get(&black_box(id)));
+        }
+    });
+}
+
+#[bench]
+fn map_lookup_id_hasher(b: &mut Bencher) {
+    map_lookup::<BuildHasherDefault<IdHasher>>(b);
+}
+
+#[bench]
+fn map_lookup_siphash(b: &mut Bencher) {
+    map_lookup::<RandomState>(b);
+}
+
+// The contended benchmarks have several threads that aren't the owner call
+// `get` at the same time, which is where the sharded map pays off.
+const CONTENDED_THREADS: usize = 4;
+const CONTENDED_GETS: usize = 10_000;
+
+fn contended<F: Fn() + Sync>(f: F) {
+    thread::scope(|s| {
+        for _ in 0..CONTENDED_THREADS {
+            s.spawn(|| {
+                for _ in 0..CONTENDED_GETS {
+                    f();
+                }
+            });
+        }
+    });
+}
+
+// These compare blocking on a briefly held lock right away with spinning on
+// `try_lock` for a while first.
+#[bench]
+fn mutex_lock_unlock_contended(b: &mut Bencher) {
+    let lock = Mutex::new(0usize);
+    b.iter(|| contended(|| *lock.lock().unwrap() += 1));
+}
+
+#[bench]
+fn mutex_spin_then_lock_contended(b: &mut Bencher) {
+    let lock = Mutex::new(0usize);
+    b.iter(|| contended(|| *spin_then_lock(&lock) += 1));
+}
+
+fn spin_then_lock<T>(lock: &Mutex<T>) -> std::sync::MutexGuard<'_, T> {
+    for i in 0..6 {
+        if let Ok(guard) = lock.try_lock() {
+            return guard;
+        }
+        for _ in 0..1 << i {
+            std::hint::spin_loop();
+        }
+    }
+    lock.lock().unwrap()
+}
+
+#[bench]
+fn mempool_get_contended(b: &mut Bencher) {
+    let pool = Pool::new(dummy());
+    let _ = pool.get();
+    b.iter(|| contended(|| { black_box(pool.get()); }));
+}
+
+#[bench]
+fn mempool_get_symmetric_contended(b: &mut Bencher) {
+    let pool = Pool::new_symmetric(dummy());
+    b.iter(|| contended(|| { black_box(pool.get()); }));
+}
+
+// Each thread only caches its value of the last pool it used, so alternating
+// between two pools takes the slow path, and thus a shard's lock, every time.
+#[bench]
+fn mempool_get_contended_uncached(b: &mut Bencher) {
+    let (pool1, pool2) = (Pool::new(dummy()), Pool::new(dummy()));
+    let _ = (pool1.get(), pool2.get());
+    b.iter(|| contended(|| {
+        black_box(pool1.get());
+        black_box(pool2.get());
+    }));
+}
+
+#[bench]
+fn single_mutex_get_contended(b: &mut Bencher) {
+    let pool = PoolSingleMutex::new(dummy());
+    b.iter(|| contended(|| { black_box(pool.get()); }));
+}
+
+// This measures the owner's fast path while other threads are hammering the
+// slow path, which shouldn't disturb the owner's cache line.
+#[bench]
+fn mempool_get_owner_with_background(b: &mut Bencher) {
+    let pool = Pool::new(dummy());
+    let _ = pool.get();
+    let done = AtomicBool::new(false);
+    thread::scope(|s| {
+        for _ in 0..CONTENDED_THREADS {
+            s.spawn(|| {
+                while !done.load(Relaxed) {
+                    black_box(pool.get());
+                }
+            });
+        }
+        b.iter(|| black_box(pool.get()));
+        done.store(true, Relaxed);
     });
 }
 
@@ -52,10 +223,7 @@ fn refcell_get_put(b: &mut Bencher) {
     let pool = PoolRefCell::new(dummy());
     let _ = pool.get();
     b.iter(|| {
-        black_box({
-            let data = pool.get();
-            drop(data);
-        })
+        black_box(pool.get());
     });
 }
 
@@ -64,10 +232,7 @@ fn mutex_get_put(b: &mut Bencher) {
     let pool = PoolMutex::new(dummy());
     let _ = pool.get();
     b.iter(|| {
-        black_box({
-            let data = pool.get();
-            drop(data);
-        })
+        black_box(pool.get());
     });
 }
 
@@ -76,10 +241,7 @@ fn mpmc_get_put(b: &mut Bencher) {
     let pool = PoolMpmc::new(dummy());
     let _ = pool.get();
     b.iter(|| {
-        black_box({
-            let data = pool.get();
-            drop(data);
-        })
+        black_box(pool.get());
     });
 }
 
@@ -88,10 +250,7 @@ fn crossbeam_treiber_get_put(b: &mut Bencher) {
     let pool = PoolTreiber::new(dummy());
     let _ = pool.get();
     b.iter(|| {
-        black_box({
-            let data = pool.get();
-            drop(data);
-        })
+        black_box(pool.get());
     });
 }
 
@@ -100,10 +259,7 @@ fn crossbeam_ms_get_put(b: &mut Bencher) {
     let pool = PoolMs::new(dummy());
     let _ = pool.get();
     b.iter(|| {
-        black_box({
-            let data = pool.get();
-            drop(data);
-        })
+        black_box(pool.get());
     });
 }
 
@@ -112,13 +268,31 @@ fn crossbeam_seg_get_put(b: &mut Bencher) {
     let pool = PoolSeg::new(dummy());
     let _ = pool.get();
     b.iter(|| {
-        black_box({
-            let data = pool.get();
-            drop(data);
-        })
+        black_box(pool.get());
     });
 }
 
+struct PoolSingleMutex<T> {
+    global: Mutex<HashMap<usize, Box<UnsafeCell<T>>>>,
+    create: CreateFn<T>,
+}
+
+unsafe impl<T: Send> Sync for PoolSingleMutex<T> {}
+
+impl<T: Send> PoolSingleMutex<T> {
+    fn new(create: CreateFn<T>) -> PoolSingleMutex<T> {
+        PoolSingleMutex { global: Mutex::new(HashMap::new()), create }
+    }
+
+    fn get(&self) -> &T {
+        let mut global = self.global.lock().unwrap();
+        let t = global
+            .entry(thread_id::current())
+            .or_insert_with(|| Box::new(UnsafeCell::new((self.create)())));
+        unsafe { &*t.get() }
+    }
+}
+
 struct PoolRefCell<T> {
     stack: RefCell<Vec<T>>,
     create: CreateFn<T>,
@@ -131,10 +305,10 @@ struct PoolRefCellGuard<'a, T: 'a> {
 
 impl<T> PoolRefCell<T> {
     fn new(create: CreateFn<T>) -> PoolRefCell<T> {
-        PoolRefCell { stack: RefCell::new(vec![]), create: create }
+        PoolRefCell { stack: RefCell::new(vec![]), create }
     }
 
-    fn get(&self) -> PoolRefCellGuard<T> {
+    fn get(&self) -> PoolRefCellGuard<'_, T> {
         let mut stack = self.stack.borrow_mut();
         match stack.pop() {
             None => {
@@ -169,10 +343,10 @@ struct PoolMutexGuard<'a, T: 'a> {
 
 impl<T> PoolMutex<T> {
     fn new(create: CreateFn<T>) -> PoolMutex<T> {
-        PoolMutex { stack: Mutex::new(vec![]), create: create }
+        PoolMutex { stack: Mutex::new(vec![]), create }
     }
 
-    fn get(&self) -> PoolMutexGuard<T> {
+    fn get(&self) -> PoolMutexGuard<'_, T> {
         let mut stack = self.stack.lock().unwrap();
         match stack.pop() {
             None => {
@@ -207,10 +381,10 @@ struct PoolMpmcGuard<'a, T: Send + 'static> {
 
 impl<T: Send + 'static> PoolMpmc<T> {
     fn new(create: CreateFn<T>) -> PoolMpmc<T> {
-        PoolMpmc { stack: ArrayQueue::with_capacity(1), create: create }
+        PoolMpmc { stack: ArrayQueue::with_capacity(1), create }
     }
 
-    fn get(&self) -> PoolMpmcGuard<T> {
+    fn get(&self) -> PoolMpmcGuard<'_, T> {
         match self.stack.pop() {
             None => {
                 PoolMpmcGuard { pool: self, data: Some((self.create)()) }
@@ -243,10 +417,10 @@ struct PoolTreiberGuard<'a, T: 'a> {
 
 impl<T> PoolTreiber<T> {
     fn new(create: CreateFn<T>) -> PoolTreiber<T> {
-        PoolTreiber { stack: TreiberStack::new(), create: create }
+        PoolTreiber { stack: TreiberStack::new(), create }
     }
 
-    fn get(&self) -> PoolTreiberGuard<T> {
+    fn get(&self) -> PoolTreiberGuard<'_, T> {
         match self.stack.pop() {
             None => {
                 PoolTreiberGuard { pool: self, data: Some((self.create)()) }
@@ -279,10 +453,10 @@ struct PoolMsGuard<'a, T: 'a> {
 
 impl<T> PoolMs<T> {
     fn new(create: CreateFn<T>) -> PoolMs<T> {
-        PoolMs { stack: MsQueue::new(), create: create }
+        PoolMs { stack: MsQueue::new(), create }
     }
 
-    fn get(&self) -> PoolMsGuard<T> {
+    fn get(&self) -> PoolMsGuard<'_, T> {
         match self.stack.try_pop() {
             None => {
                 PoolMsGuard { pool: self, data: Some((self.create)()) }
@@ -315,10 +489,10 @@ struct PoolSegGuard<'a, T: 'a> {
 
 impl<T> PoolSeg<T> {
     fn new(create: CreateFn<T>) -> PoolSeg<T> {
-        PoolSeg { stack: SegQueue::new(), create: create }
+        PoolSeg { stack: SegQueue::new(), create }
     }
 
-    fn get(&self) -> PoolSegGuard<T> {
+    fn get(&self) -> PoolSegGuard<'_, T> {
         match self.stack.try_pop() {
             None => {
                 PoolSegGuard { pool: self, data: Some((self.create)()) }
@@ -0,0 +1,292 @@
+/*!
+Pools of byte buffers and other collections.
+
+Pooling `Vec<u8>` buffers is the most common use of this crate. A
+`BufferPool` hands out buffers with `acquire`, each of which is empty and has
+at least the capacity the pool was configured with. When a buffer is dropped,
+it's returned to the pool, and its contents are cleared (but its allocation
+is kept) the next time it's acquired.
+
+```rust
+use std::io::Write;
+
+use mempool::buffer::BufferPool;
+
+let pool = BufferPool::with_capacity(4096);
+let mut buf = pool.acquire();
+assert!(buf.is_empty() && buf.capacity() >= 4096);
+write!(buf, "hello").unwrap();
+```
+
+A `SecureBufferPool` is the same, except that each buffer is zeroed as soon as
+it's dropped, rather than merely cleared the next time it's acquired. This is
+for buffers that hold secrets. Scrubbing costs time proportional to the
+capacity of the buffer on every release, which gives up some of the benefit
+of reusing it. With the `zeroize` feature, buffers are scrubbed by the
+[zeroize](https://docs.rs/zeroize) crate. Otherwise, they're scrubbed with
+volatile writes, which the compiler can't optimize away.
+
+A `ReservingPool` generalizes `BufferPool` to any collection that implements
+`Reserve`, such as a `String` or a `HashMap`. Each collection it hands out is
+empty and has room for at least the configured number of elements.
+
+```rust
+use mempool::buffer::ReservingPool;
+
+let pool = ReservingPool::<String>::with_capacity(64);
+let mut s = pool.acquire();
+assert!(s.is_empty() && s.capacity() >= 64);
+s.push_str("hello");
+```
+*/
+
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::hash::{BuildHasher, Hash};
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+
+use {CreateFn, Owner, Pool, PoolGuard};
+
+/// A pool of byte buffers.
+///
+/// See the module documentation for details.
+#[derive(Debug)]
+pub struct BufferPool {
+    pool: ReservingPool<Vec<u8>>,
+}
+
+impl Default for BufferPool {
+    fn default() -> BufferPool {
+        BufferPool::new()
+    }
+}
+
+impl BufferPool {
+    /// Create a new pool of buffers with no minimum capacity.
+    pub fn new() -> BufferPool {
+        BufferPool::with_capacity(0)
+    }
+
+    /// Create a new pool of buffers, each with a capacity of at least
+    /// `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> BufferPool {
+        BufferPool { pool: ReservingPool::with_capacity(capacity) }
+    }
+
+    /// Acquire an empty buffer from the pool.
+    ///
+    /// The buffer is returned to the pool when the guard is dropped. Like
+    /// `Pool::checkout`, a buffer is only ever reused by the thread that
+    /// acquired it.
+    pub fn acquire(&self) -> PoolGuard<'_, Vec<u8>> {
+        self.pool.acquire()
+    }
+}
+
+/// A collection that can be emptied while keeping its allocation, and that
+/// can reserve room for more elements.
+pub trait Reserve {
+    /// Reserve room for at least `additional` more elements.
+    fn reserve(&mut self, additional: usize);
+
+    /// Remove every element, keeping the allocation.
+    fn clear(&mut self);
+}
+
+impl<T> Reserve for Vec<T> {
+    fn reserve(&mut self, additional: usize) {
+        Vec::reserve(self, additional);
+    }
+
+    fn clear(&mut self) {
+        Vec::clear(self);
+    }
+}
+
+impl<T> Reserve for VecDeque<T> {
+    fn reserve(&mut self, additional: usize) {
+        VecDeque::reserve(self, additional);
+    }
+
+    fn clear(&mut self) {
+        VecDeque::clear(self);
+    }
+}
+
+impl Reserve for String {
+    fn reserve(&mut self, additional: usize) {
+        String::reserve(self, additional);
+    }
+
+    fn clear(&mut self) {
+        String::clear(self);
+    }
+}
+
+impl<K: Eq + Hash, V, S: BuildHasher> Reserve for HashMap<K, V, S> {
+    fn reserve(&mut self, additional: usize) {
+        HashMap::reserve(self, additional);
+    }
+
+    fn clear(&mut self) {
+        HashMap::clear(self);
+    }
+}
+
+impl<T: Eq + Hash, S: BuildHasher> Reserve for HashSet<T, S> {
+    fn reserve(&mut self, additional: usize) {
+        HashSet::reserve(self, additional);
+    }
+
+    fn clear(&mut self) {
+        HashSet::clear(self);
+    }
+}
+
+/// A pool of collections, each of which is empty and has room for a
+/// configured number of elements when it's acquired.
+///
+/// See the module documentation for details.
+pub struct ReservingPool<T: Send> {
+    pool: Pool<T>,
+}
+
+impl<T: Default + Reserve + Send + 'static> Default for ReservingPool<T> {
+    fn default() -> ReservingPool<T> {
+        ReservingPool::new()
+    }
+}
+
+impl<T: Default + Reserve + Send + 'static> ReservingPool<T> {
+    /// Create a new pool of collections with no minimum capacity.
+    pub fn new() -> ReservingPool<T> {
+        ReservingPool::with_capacity(0)
+    }
+
+    /// Create a new pool of collections, each with room for at least
+    /// `capacity` elements.
+    pub fn with_capacity(capacity: usize) -> ReservingPool<T> {
+        let create: CreateFn<T> = Box::new(move || {
+            let mut value = T::default();
+            value.reserve(capacity);
+            value
+        });
+        // Collections are only ever handed out by `checkout`, so the owner's
+        // value is never created.
+        let mut pool = Pool::from_parts(create, Owner::new(None));
+        pool.reset = Some(Arc::new(move |value: &mut T| {
+            value.clear();
+            value.reserve(capacity);
+        }));
+        ReservingPool { pool }
+    }
+
+    /// Acquire an empty collection from the pool.
+    ///
+    /// Like `BufferPool::acquire`, the collection is returned to the pool
+    /// when the guard is dropped, and is only ever reused by the thread that
+    /// acquired it. Its contents are cleared the next time it's acquired.
+    pub fn acquire(&self) -> PoolGuard<'_, T> {
+        self.pool.checkout()
+    }
+}
+
+impl<T: Send> fmt::Debug for ReservingPool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ReservingPool").field("pool", &self.pool).finish()
+    }
+}
+
+/// A pool of byte buffers that are zeroed when they're dropped.
+///
+/// See the module documentation for details.
+#[derive(Debug, Default)]
+pub struct SecureBufferPool {
+    pool: BufferPool,
+}
+
+impl SecureBufferPool {
+    /// Create a new pool of buffers with no minimum capacity.
+    pub fn new() -> SecureBufferPool {
+        SecureBufferPool::with_capacity(0)
+    }
+
+    /// Create a new pool of buffers, each with a capacity of at least
+    /// `capacity` bytes.
+    pub fn with_capacity(capacity: usize) -> SecureBufferPool {
+        SecureBufferPool { pool: BufferPool::with_capacity(capacity) }
+    }
+
+    /// Acquire an empty buffer from the pool.
+    ///
+    /// When the buffer is dropped, its entire capacity is zeroed before it's
+    /// returned to the pool.
+    pub fn acquire(&self) -> SecureBuffer<'_> {
+        SecureBuffer { buf: self.pool.acquire() }
+    }
+}
+
+/// A buffer acquired from a `SecureBufferPool`.
+///
+/// The buffer dereferences to a `Vec<u8>`. Its contents are never printed by
+/// its `Debug` impl.
+pub struct SecureBuffer<'a> {
+    buf: PoolGuard<'a, Vec<u8>>,
+}
+
+impl<'a> Deref for SecureBuffer<'a> {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8> {
+        &self.buf
+    }
+}
+
+impl<'a> DerefMut for SecureBuffer<'a> {
+    fn deref_mut(&mut self) -> &mut Vec<u8> {
+        &mut self.buf
+    }
+}
+
+impl<'a> Drop for SecureBuffer<'a> {
+    fn drop(&mut self) {
+        // This runs before the guard is dropped, which returns the buffer
+        // to the pool.
+        scrub(&mut self.buf);
+    }
+}
+
+impl<'a> fmt::Debug for SecureBuffer<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("SecureBuffer").field("len", &self.len()).finish()
+    }
+}
+
+/// Zeroes the entire capacity of `buf` and clears it.
+///
+/// Bytes past the length of the buffer are zeroed too, since they may hold
+/// data that was truncated.
+#[cfg(feature = "zeroize")]
+fn scrub(buf: &mut Vec<u8>) {
+    zeroize::Zeroize::zeroize(buf);
+}
+
+/// Zeroes the entire capacity of `buf` and clears it.
+///
+/// Bytes past the length of the buffer are zeroed too, since they may hold
+/// data that was truncated.
+#[cfg(not(feature = "zeroize"))]
+fn scrub(buf: &mut Vec<u8>) {
+    use std::ptr;
+    use std::sync::atomic::{Ordering, compiler_fence};
+
+    let ptr = buf.as_mut_ptr();
+    for i in 0..buf.capacity() {
+        // Volatile writes can't be elided, even though the bytes are never
+        // read again.
+        unsafe { ptr::write_volatile(ptr.add(i), 0) };
+    }
+    compiler_fence(Ordering::SeqCst);
+    buf.clear();
+}
@@ -0,0 +1,107 @@
+/*!
+A pool with one value per key, rather than one value per thread.
+
+A `KeyedPool` creates the value for a key the first time it's requested, and
+returns the same value for that key from then on, on any thread. Like the
+values of a `Pool`, a value never moves once it has been created, so the
+references returned by `get` stay valid for as long as the pool is borrowed.
+
+Since values aren't tied to threads, there is no owner and no fast path:
+every call to `get` takes a lock and looks the key up in a map. And since any
+thread can get the value of any key, the values must be `Sync`.
+
+```rust
+use mempool::keyed::KeyedPool;
+
+let pool = KeyedPool::new(Box::new(|id: &u64| format!("session {}", id)));
+assert_eq!("session 5", pool.get(&5));
+assert!(std::ptr::eq(pool.get(&5), pool.get(&5)));
+```
+*/
+
+use std::collections::HashMap;
+use std::collections::hash_map::Entry;
+use std::fmt;
+use std::hash::Hash;
+use std::mem;
+
+use Mutex;
+
+/// The type of the initialization function of a `KeyedPool`.
+///
+/// It's given the key that the value is being created for.
+pub type KeyedCreateFn<K, T> = Box<dyn Fn(&K) -> T + Send + Sync + 'static>;
+
+/// A pool holding one value for each key.
+///
+/// See the module documentation for details.
+pub struct KeyedPool<K, T, F = KeyedCreateFn<K, T>> {
+    create: F,
+    // Each value is boxed, so that it doesn't move when the map grows.
+    values: Mutex<HashMap<K, Box<T>>>,
+}
+
+impl<K: Eq + Hash, T> KeyedPool<K, T> {
+    /// Create a new pool with the given initialization function.
+    pub fn new(create: KeyedCreateFn<K, T>) -> KeyedPool<K, T> {
+        KeyedPool::with_fn(create)
+    }
+}
+
+impl<K: Eq + Hash, T, F: Fn(&K) -> T> KeyedPool<K, T, F> {
+    /// Create a new pool with any initialization function.
+    ///
+    /// Like `Pool::with_fn`, this avoids boxing the closure.
+    pub fn with_fn(create: F) -> KeyedPool<K, T, F> {
+        KeyedPool { create, values: Mutex::new(HashMap::new()) }
+    }
+
+    /// Get a reference to the value for `key`, creating it if necessary.
+    ///
+    /// The initialization function is called without holding the lock, so it
+    /// may use the pool itself. If two threads create a value for the same
+    /// key at once, then one of the values is dropped, and both threads get
+    /// the other.
+    pub fn get(&self, key: &K) -> &T where K: Clone, T: Sync {
+        if let Some(val) = self.values.lock().unwrap().get(key) {
+            return unsafe { &*(&**val as *const T) };
+        }
+        let value = Box::new((self.create)(key));
+        let mut values = self.values.lock().unwrap();
+        let (val, unused) = match values.entry(key.clone()) {
+            Entry::Occupied(e) => (&**e.get() as *const T, Some(value)),
+            Entry::Vacant(e) => (&**e.insert(value) as *const T, None),
+        };
+        // The unused value is dropped after the lock is released, so that a
+        // panic in its destructor can't poison the lock.
+        drop(values);
+        drop(unused);
+        // A value is never removed while the pool is borrowed, and boxed
+        // values don't move, so the reference outlives the lock.
+        unsafe { &*val }
+    }
+}
+
+impl<K, T, F> KeyedPool<K, T, F> {
+    /// Returns the number of keys with a value.
+    pub fn len(&self) -> usize {
+        self.values.lock().unwrap().len()
+    }
+
+    /// Returns true if no key has a value.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Consume the pool and return every key and its value.
+    pub fn into_inner(mut self) -> HashMap<K, T> where K: Eq + Hash {
+        let values = mem::take(self.values.get_mut().unwrap());
+        values.into_iter().map(|(key, val)| (key, *val)).collect()
+    }
+}
+
+impl<K, T, F> fmt::Debug for KeyedPool<K, T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KeyedPool").field("len", &self.len()).finish()
+    }
+}
@@ -10,65 +10,1187 @@ A pool takes an initialization function for creating members of the pool. Once
 created, values can be immediately retrieved.
 
 ```rust
+# #[cfg(feature = "std")] {
 use mempool::Pool;
 
 let pool = Pool::new(Box::new(|| "foobar"));
 assert_eq!("foobar", *pool.get());
+# }
+```
+
+If the initialization function is a plain closure, then `Pool::with_fn` stores
+it inline, which avoids boxing it and calling it through dynamic dispatch:
+
+```rust
+# #[cfg(feature = "std")] {
+use mempool::Pool;
+
+let pool = Pool::with_fn(|| Vec::<u8>::with_capacity(4096));
+assert!(pool.get().capacity() >= 4096);
+# }
 ```
 
 Note that the pool returns an immutable reference. If you need a mutable
 reference, then use a `RefCell`. (Which is guaranteed safe by the pool.)
+Alternatively, `Pool::get_mut` returns a mutable reference without the runtime
+borrow checking of a `RefCell`, but it is `unsafe` since the caller must ensure
+that it doesn't alias another reference to the same value.
+
+# Sharing a pool
+
+A pool is `Sync` (as long as its initialization function is), so threads can
+share it by reference. Nothing about the pool assumes it's wrapped in an
+`Arc`: a thread that exits leaves its values in the pool until the pool is
+dropped, no matter how the pool is shared. A pool that lives for the whole
+program can be a `static` (see `pool!`), or leaked, so that each thread
+captures a `&'static Pool` without the reference counting of an `Arc`:
+
+```rust
+# #[cfg(feature = "std")] {
+use mempool::Pool;
+
+let pool: &'static Pool<Vec<u8>> = Box::leak(Box::new(Pool::new(Box::new(
+    || Vec::with_capacity(4096),
+))));
+let handles: Vec<_> = (0..4).map(|_| {
+    std::thread::spawn(move || pool.get().capacity())
+}).collect();
+for h in handles {
+    assert!(h.join().unwrap() >= 4096);
+}
+# }
+```
+
+# Crate features
+
+* **std** - Enabled by default. When disabled, this crate only requires
+  `alloc`. Values are then kept in a `BTreeMap` guarded by a spin lock, and
+  since there are no thread locals, callers must identify the current thread
+  themselves with `Pool::get_with_id`.
+* **parking_lot** - Uses the mutex from
+  [parking_lot](https://docs.rs/parking_lot) for the slow path instead of
+  std's. Its locks are smaller, are faster to acquire when uncontended, and
+  are never poisoned. This implies `std`.
+* **numa** - Gives each thread an id whose shard is reserved for the NUMA
+  node it starts on, so that the threads on a node only ever take the locks
+  of, and find their values in, shards used by that node. On a multi-socket
+  machine, this keeps the cache lines of a shard's lock from bouncing
+  between sockets when many threads on different nodes use the slow path at
+  once. Gains are only expected there, e.g., in the
+  `mempool_get_contended_uncached` benchmark run with threads spread over
+  the nodes. On a single node, or when the nodes can't be determined (e.g.,
+  on platforms other than Linux), ids are assigned as without this feature.
+  This implies `std`.
+* **debug-tracking** - Makes `Pool::get_tracked` count the references it
+  hands out on each thread, so that `Pool::remove_current_thread` and
+  `Pool::swap_current_thread` panic if the current thread still holds one,
+  instead of causing undefined behavior.
+  This is meant for tests. Without this feature, `get_tracked` is just
+  `get`. This implies `std`.
+* **serde** - Implements `Serialize` for `Pool`, for including a pool in a
+  dump of a program's state. Only the owner's value is serialized, as an
+  `Option` that is `None` until it's created. The values of other threads
+  are scratch space, so they're omitted. There's no `Deserialize` impl,
+  since the initialization function can't be deserialized.
+* **stats** - Counts how often `get` finds an existing value, for tuning.
+  The counts are returned by `Pool::stats`. Without this feature, nothing is
+  counted.
+* **tokio** - Provides the `task` module, which gives each async task running
+  on [tokio](https://docs.rs/tokio) its own value, rather than each thread.
+  This implies `std`.
+* **tracing** - Emits a `TRACE` level event with
+  [tracing](https://docs.rs/tracing) every time a pool creates a value for
+  a thread, with the thread's id. Without this feature, no event code is
+  compiled in at all.
+* **zeroize** - Scrubs the buffers of `buffer::SecureBufferPool` with
+  [zeroize](https://docs.rs/zeroize) instead of plain volatile writes. This
+  implies `std`.
+* **loom** - Swaps the synchronization primitives used by a pool for those of
+  [loom](https://docs.rs/loom), for model checking. This is only useful for
+  testing this crate. Run the models with
+  `cargo test --release --features loom --lib model`: loom's primitives
+  panic outside of a model, so the doctests can't run with this feature.
+
+# Scoped pools
+
+The initialization function of a pool may borrow from its environment. The
+`scoped` module describes how this interacts with threads and when values are
+dropped.
+
+# Thread-local values
+
+Values that aren't `Send` can't be put in a `Pool`. The `local` module
+provides a pool for a value that never leaves the thread that created it.
+
+# Keyed pools
+
+The `keyed` module provides a pool with one value per key, e.g., per
+session, rather than one value per thread.
 */
 #![deny(missing_docs)]
-#![cfg_attr(feature = "nightly", feature(test))]
+#![cfg_attr(all(test, feature = "nightly"), feature(test))]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
+#[cfg(feature = "std")]
+extern crate core;
+#[cfg(feature = "numa")]
+extern crate libc;
+#[cfg(feature = "loom")]
+extern crate loom;
+#[cfg(feature = "parking_lot")]
+extern crate parking_lot;
+#[cfg(feature = "serde")]
+extern crate serde;
+#[cfg(feature = "tokio")]
+extern crate tokio;
+#[cfg(feature = "tracing")]
+extern crate tracing;
+#[cfg(feature = "zeroize")]
+extern crate zeroize;
+
+use alloc::boxed::Box;
+#[cfg(feature = "std")]
+use alloc::rc::Rc;
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec::Vec;
+use core::array;
+use core::cell::UnsafeCell;
+use core::convert::Infallible;
+use core::fmt;
+use core::marker::PhantomData;
+#[cfg(feature = "std")]
+use core::hash::{BuildHasherDefault, Hash, Hasher};
+use core::mem::{self, MaybeUninit};
+use core::ops::{ControlFlow, Deref, DerefMut};
+use core::ptr;
+use core::sync::atomic::Ordering::{AcqRel, Acquire, Relaxed, Release};
 
-use std::collections::hash_map::{HashMap, Entry};
-use std::fmt;
+use shard::Shard;
+use Event::{Hit, Miss};
+
+// Under the `loom` feature, the synchronization primitives used by a pool are
+// swapped for loom's, so that its model checker can explore their
+// interleavings. Without the `std` feature, a spin lock is used instead of
+// std's mutex, and with the `parking_lot` feature, parking_lot's mutex is.
+#[cfg(feature = "loom")]
+use loom::sync::Mutex;
+#[cfg(feature = "loom")]
+use loom::sync::atomic::AtomicUsize;
+#[cfg(all(feature = "parking_lot", not(feature = "loom")))]
+use parking::Mutex;
+#[cfg(all(
+    feature = "std",
+    not(feature = "parking_lot"),
+    not(feature = "loom"),
+))]
 use std::sync::Mutex;
-use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT};
-use std::sync::atomic::Ordering::Relaxed;
-
-// A counter provides the basis for assigning an id to each thread that tries
-// to access the pool. In particular, the first thread to access a pool becomes
-// its owner, and correspondingly is the only thread with access to the "fast"
-// path.
-//
-// The thread id `0` is a special sentinel value to indicate that the pool has
-// no owner yet. Therefore, all thread ids assigned to a thread start from `1`.
-static COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
-thread_local!(static THREAD_ID: usize = COUNTER.fetch_add(1, Relaxed) + 1);
+#[cfg(not(feature = "std"))]
+use spin::Mutex;
+#[cfg(not(feature = "loom"))]
+use core::sync::atomic::AtomicUsize;
+
+// Values are keyed by thread id. Without `std`, there is no `HashMap`, so a
+// `BTreeMap` is used instead.
+#[cfg(feature = "std")]
+use std::collections::HashMap;
+#[cfg(not(feature = "std"))]
+use alloc::collections::BTreeMap;
+
+#[cfg(feature = "std")]
+type Map<V> = HashMap<usize, V, BuildHasherDefault<IdHasher>>;
+#[cfg(not(feature = "std"))]
+type Map<V> = BTreeMap<usize, V>;
+
+#[cfg(feature = "std")]
+const fn new_map<V>() -> Map<V> {
+    HashMap::with_hasher(BuildHasherDefault::new())
+}
+
+#[cfg(not(feature = "std"))]
+const fn new_map<V>() -> Map<V> {
+    BTreeMap::new()
+}
+
+/// A hasher for thread ids.
+///
+/// Thread ids are small integers handed out by this crate, so they don't need
+/// the DoS resistance of SipHash. Instead, they are multiplied by a large odd
+/// constant to spread them over all of the bits of the hash. Unlike std's
+/// `RandomState`, this can be created in a `const fn`.
+#[cfg(feature = "std")]
+#[derive(Default)]
+struct IdHasher(u64);
+
+#[cfg(feature = "std")]
+impl Hasher for IdHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &b in bytes {
+            self.write_u8(b);
+        }
+    }
+
+    fn write_u8(&mut self, n: u8) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_usize(&mut self, n: usize) {
+        self.write_u64(n as u64);
+    }
+
+    fn write_u64(&mut self, n: u64) {
+        self.0 = (self.0 ^ n).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    }
+}
+
+#[cfg(not(feature = "std"))]
+mod spin;
+#[cfg(all(feature = "parking_lot", not(feature = "loom")))]
+mod parking;
+#[cfg(feature = "numa")]
+mod numa;
+mod shard;
+#[cfg(feature = "std")]
+mod thread_id;
+
+#[cfg(feature = "std")]
+pub mod buffer;
+#[cfg(feature = "std")]
+pub mod keyed;
+#[cfg(feature = "std")]
+pub mod local;
+pub mod scoped;
+#[cfg(feature = "tokio")]
+pub mod task;
+
+/// The number of shards of the map holding the values of non-owner threads.
+///
+/// Each shard has its own lock, and a thread's value lives in the shard given
+/// by its thread id modulo `SHARDS`. So non-owner threads only contend with
+/// the other threads that share their shard.
+pub const SHARDS: usize = 8;
+
+/// Declare a pool as a `static`.
+///
+/// The initialization function must not capture anything, so that it can be
+/// stored as a function pointer. The pool is created with `Pool::new_const`,
+/// so no value is created until the first call to `get`.
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate mempool;
+///
+/// pool!(static SCRATCH: Vec<u8> = || Vec::with_capacity(8192));
+///
+/// fn main() {
+/// # #[cfg(feature = "std")]
+///     assert!(SCRATCH.get().capacity() >= 8192);
+/// }
+/// ```
+///
+/// Several pools may be declared at once, and each may have attributes and
+/// a visibility.
+#[cfg(not(feature = "loom"))]
+#[macro_export]
+macro_rules! pool {
+    ($(
+        $(#[$attr:meta])*
+        $vis:vis static $name:ident: $t:ty = $create:expr;
+    )+) => {
+        $(
+            $(#[$attr])*
+            $vis static $name: $crate::Pool<$t, fn() -> $t> =
+                $crate::Pool::new_const($create);
+        )+
+    };
+    (
+        $(#[$attr:meta])*
+        $vis:vis static $name:ident: $t:ty = $create:expr
+    ) => {
+        $crate::pool!($(#[$attr])* $vis static $name: $t = $create;);
+    };
+}
+
+/// Returns the id that pools assign to the current thread.
+///
+/// This is the id that `Pool::get` uses. Ids are never `0`, and once a thread
+/// exits, its id may be reused by another thread. So it's safe to pass this
+/// id to `Pool::get_with_id`, but an id returned on another thread may not
+/// be.
+#[cfg(feature = "std")]
+pub fn current_thread_id() -> usize {
+    thread_id::current()
+}
 
 /// The type of an initialization function.
-pub type CreateFn<T> = Box<Fn() -> T + Send + Sync + 'static>;
+///
+/// This is the default creator type of a `Pool`. Use `Pool::with_fn` to
+/// store a closure inline instead, or see the `scoped` module for a boxed
+/// closure that isn't `'static`.
+pub type CreateFn<T> = scoped::ScopedCreateFn<'static, T>;
+
+/// The type of a fallible initialization function.
+///
+/// A pool with this creator type is built with `Pool::try_new`, and its
+/// values are retrieved with `Pool::try_get`. The error type `E` is chosen by
+/// the caller.
+pub type TryCreateFn<T, E> =
+    Box<dyn Fn() -> Result<T, E> + Send + Sync + 'static>;
+
+/// The type of a reset function.
+///
+/// A reset function is registered with `Pool::with_reset`. It is called on a
+/// value before it is reused, e.g., to clear a buffer while keeping its
+/// capacity.
+pub type ResetFn<T> = Box<dyn Fn(&mut T) + Send + Sync + 'static>;
+
+// A reset function is shared, so that a clone of a pool has the same one.
+type SharedResetFn<T> = Arc<dyn Fn(&mut T) + Send + Sync + 'static>;
+
+/// The type of a function that finalizes values.
+///
+/// A finalizer is registered with `Pool::with_finalizer`. It is given every
+/// value that the pool discards, in place of dropping it.
+pub type FinalizeFn<T> = Box<dyn Fn(T) + Send + Sync + 'static>;
+
+// Shared for the same reason as a reset function.
+type SharedFinalizeFn<T> = Arc<dyn Fn(T) + Send + Sync + 'static>;
 
 /// A fast memory pool.
-pub struct Pool<T: Send> {
-    create: CreateFn<T>,
-    owner: AtomicUsize,
-    owner_val: T,
-    global: Mutex<HashMap<usize, Box<T>>>,
+///
+/// Each thread that uses the pool gets its own value. When a thread exits, its
+/// value isn't dropped, and it isn't handed to another thread either, since
+/// the exiting thread's thread local destructors may still be using it.
+/// Instead, it is kept until the pool is cleared or dropped, unless the
+/// thread asked for it to be dropped with `cleanup_on_thread_exit`.
+///
+/// The values must be `Send`, since every value is dropped by whichever
+/// thread drops the pool, and the owner's value may be taken over by another
+/// thread with `adopt`. So a pool of `Rc`s is rejected, with an error that
+/// `Rc` can't be sent between threads safely:
+///
+/// ```compile_fail,E0277
+/// use std::rc::Rc;
+///
+/// use mempool::Pool;
+///
+/// let pool = Pool::new(Box::new(|| Rc::new(5)));
+/// ```
+///
+/// A value that isn't `Send` can be kept in a `local::LocalPool` instead.
+///
+/// The type parameter `F` is the type of the initialization function. It
+/// defaults to a boxed closure, which is what `Pool::new` accepts.
+pub struct Pool<T: Send, F = CreateFn<T>> {
+    create: F,
+    reset: Option<SharedResetFn<T>>,
+    finalize: Option<SharedFinalizeFn<T>>,
+    uid: AtomicUsize,
+    owner: Owner<T>,
+    // The id of the thread that last became the owner, and its flag that
+    // says whether it's still alive, if it was the current thread. This is
+    // only used by `adopt`, so it's kept off the owner's cache line.
+    #[cfg(feature = "std")]
+    owner_alive: std::sync::Mutex<Option<(usize, thread_id::Alive)>>,
+    // The values that `get_with_init` has initialized: the address of each
+    // thread's value in the map, keyed by the id of the thread, and `0`
+    // keyed by `0` for the owner's value, whose address changes when the
+    // pool moves. An entry is removed when its value is moved out.
+    #[cfg(feature = "std")]
+    inited: std::sync::Mutex<Map<usize>>,
+    global: [Mutex<Shard<T>>; SHARDS],
+    free: Mutex<Free<T>>,
+    max_idle: usize,
+    max_idle_per_thread: usize,
+    symmetric: bool,
+    #[cfg(feature = "stats")]
+    stats: Stats,
+    spare: Mutex<Vec<T>>,
+}
+
+unsafe impl<T: Send, F: Sync> Sync for Pool<T, F> {}
+
+/// The state read on the fast path: the owner's thread id and its value.
+///
+/// This is aligned to its own cache line so that writes by other threads on
+/// the slow path (e.g., to the locks of the map) don't invalidate the cache
+/// line that the owner reads on every call to `get`.
+///
+/// The pool's generation lives here too, since the owner compares it with the
+/// generation of its value on every call.
+///
+/// `gen` is the generation the value was created in, or `0` if it hasn't been
+/// created yet. It's only ever written by the owner, or through `&mut`. It's
+/// atomic so that `peek_owner` can read it from any thread, and since the
+/// owner publishes its value by writing `gen`, `val` is only ever accessed by
+/// the owner, through `&mut`, or through the shared references returned by
+/// `peek_owner` once `gen` says it has been created.
+#[repr(align(64))]
+struct Owner<T> {
+    id: AtomicUsize,
+    generation: AtomicUsize,
+    gen: AtomicUsize,
+    val: UnsafeCell<MaybeUninit<T>>,
+}
+
+impl<T> Owner<T> {
+    fn new(val: Option<T>) -> Owner<T> {
+        let gen = val.is_some() as usize;
+        let val = val.map_or_else(MaybeUninit::uninit, MaybeUninit::new);
+        Owner {
+            id: AtomicUsize::new(0),
+            generation: AtomicUsize::new(1),
+            gen: AtomicUsize::new(gen),
+            val: UnsafeCell::new(val),
+        }
+    }
+
+    /// Moves the owner's value out, if it has been created.
+    fn take(&mut self) -> Option<T> {
+        if self.gen.swap(0, Relaxed) != 0 {
+            Some(unsafe { self.val.get_mut().assume_init_read() })
+        } else {
+            None
+        }
+    }
+
+    /// Returns a pointer to the owner's value if `id` is the owner and its
+    /// value was created in the current generation.
+    #[inline(always)]
+    fn get(&self, id: usize) -> Option<*mut T> {
+        // Only the owner writes `gen`, so it can read it without any
+        // synchronization. A value that hasn't been created never matches,
+        // since generations start from `1`.
+        if self.id.load(Acquire) == id
+            && self.gen.load(Relaxed) == self.generation.load(Relaxed)
+        {
+            Some(self.val.get().cast())
+        } else {
+            None
+        }
+    }
+}
+
+impl<T> Drop for Owner<T> {
+    fn drop(&mut self) {
+        if self.gen.load(Relaxed) != 0 {
+            unsafe { ptr::drop_in_place(self.val.get_mut().as_mut_ptr()) }
+        }
+    }
+}
+
+/// The values returned to a pool by `PoolGuard`, keyed by the thread that
+/// checked them out.
+struct Free<T> {
+    lists: Map<Vec<T>>,
+    len: usize,
+}
+
+impl<T> Free<T> {
+    const fn new() -> Free<T> {
+        Free { lists: new_map(), len: 0 }
+    }
+
+    #[cfg(feature = "std")]
+    fn pop(&mut self, thread_id: usize) -> Option<T> {
+        let value = self.lists.get_mut(&thread_id)?.pop()?;
+        self.len -= 1;
+        Some(value)
+    }
+
+    /// Removes up to `n` values from the free list of the given thread.
+    #[cfg(feature = "std")]
+    fn pop_many(&mut self, thread_id: usize, n: usize) -> Vec<T> {
+        let values = match self.lists.get_mut(&thread_id) {
+            Some(list) => list.split_off(list.len().saturating_sub(n)),
+            None => Vec::new(),
+        };
+        self.len -= values.len();
+        values
+    }
+
+    /// Adds a value to the free list of the given thread, unless there are
+    /// already `max` values, or `max_per_thread` values for the given
+    /// thread, in which case the value is given back.
+    fn push(
+        &mut self,
+        thread_id: usize,
+        value: T,
+        max: usize,
+        max_per_thread: usize,
+    ) -> Option<T> {
+        if self.len >= max {
+            return Some(value);
+        }
+        let list = self.lists.entry(thread_id).or_default();
+        if list.len() >= max_per_thread {
+            return Some(value);
+        }
+        list.push(value);
+        self.len += 1;
+        None
+    }
+
+    fn clear(&mut self) {
+        self.len = 0;
+        // Unlike clearing the map, dropping an iterator over it keeps
+        // dropping the remaining values if one of them panics.
+        mem::take(&mut self.lists).into_iter().for_each(drop);
+    }
+
+    fn shrink_to_fit(&mut self) {
+        self.lists.retain(|_, values| !values.is_empty());
+        for values in self.lists.values_mut() {
+            values.shrink_to_fit();
+        }
+        #[cfg(feature = "std")]
+        self.lists.shrink_to_fit();
+    }
+
+    fn values(&self) -> impl Iterator<Item = &T> {
+        self.lists.values().flatten()
+    }
+
+    fn into_values(self) -> impl Iterator<Item = T> {
+        self.lists.into_values().flatten()
+    }
+}
+
+/// Dropping a pool drops the values of every thread other than the owner,
+/// including values held for `checkout` and spare values, before it drops the
+/// owner's value. So the other values may, e.g., rely on a shared handle held
+/// by the owner's value being alive.
+///
+/// If dropping a value panics, then the remaining values are still dropped as
+/// the panic unwinds, and the owner's value is still dropped last.
+impl<T: Send, F> Drop for Pool<T, F> {
+    fn drop(&mut self) {
+        self.finalize_all();
+        Others(self).drop_all();
+        // The owner's value is dropped along with the `owner` field.
+    }
+}
+
+/// The values of a pool being dropped, other than the owner's.
+///
+/// If dropping one of them panics, then this is dropped as the panic unwinds,
+/// which drops the rest before the fields of the pool (and so the owner's
+/// value) are dropped.
+struct Others<'a, T: Send + 'a, F: 'a>(&'a mut Pool<T, F>);
+
+impl<'a, T: Send, F> Others<'a, T, F> {
+    fn drop_all(&mut self) {
+        // A poisoned lock still guards consistent data, since nothing that
+        // can panic modifies the data while a lock is held, so the values
+        // are dropped even if some thread panicked. Clearing is idempotent,
+        // so after a panic, this picks up where it left off.
+        for shard in &mut self.0.global {
+            shard.get_mut().recover().clear();
+        }
+        self.0.free.get_mut().recover().clear();
+        self.0.spare.get_mut().recover().clear();
+    }
+}
+
+impl<'a, T: Send, F> Drop for Others<'a, T, F> {
+    fn drop(&mut self) {
+        self.drop_all();
+    }
+}
+
+/// Cloning a pool creates a new, empty pool with the same initialization
+/// function and configuration.
+///
+/// The values cached by the original pool aren't cloned. Like `with_fn`, the
+/// new pool creates its owner's value immediately.
+///
+/// Since a boxed closure isn't `Clone`, this is only available for pools
+/// created with `with_fn` or `new_const`. A closure that captures an `Arc`
+/// can be used to share state between the clones.
+impl<T, F> Clone for Pool<T, F>
+        where T: Send, F: Fn() -> T + Send + Sync + Clone {
+    fn clone(&self) -> Pool<T, F> {
+        let mut pool = Pool::with_fn(self.create.clone());
+        pool.reset = self.reset.clone();
+        pool.finalize = self.finalize.clone();
+        pool.max_idle = self.max_idle;
+        pool.max_idle_per_thread = self.max_idle_per_thread;
+        pool
+    }
+}
+
+/// Statistics about how often `get` found an existing value.
+///
+/// These are returned by `Pool::stats`, which requires the `stats` feature.
+#[cfg(feature = "stats")]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct PoolStats {
+    /// The number of times a thread's existing value was returned.
+    pub hits: usize,
+    /// The number of times a new value had to be created for a thread.
+    pub misses: usize,
+    /// The number of threads with a value, as returned by `Pool::len`.
+    pub threads: usize,
+}
+
+/// The error returned by `Pool::get_checked`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PoolError {
+    /// A lock guarding the pool was poisoned by a thread that panicked while
+    /// holding it.
+    Poisoned,
+    /// The initialization function panicked.
+    CreateFailed,
+}
+
+impl fmt::Display for PoolError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PoolError::Poisoned => write!(f, "pool lock poisoned"),
+            PoolError::CreateFailed => {
+                write!(f, "pool initialization function panicked")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PoolError {}
+
+/// The counters behind `PoolStats`.
+///
+/// Every thread updates these, so they're kept on their own cache line,
+/// away from the owner's state.
+#[cfg(feature = "stats")]
+#[repr(align(64))]
+struct Stats {
+    hits: AtomicUsize,
+    misses: AtomicUsize,
 }
 
-unsafe impl<T: Send> Sync for Pool<T> {}
+#[cfg(feature = "stats")]
+impl Stats {
+    fn new() -> Stats {
+        Stats { hits: AtomicUsize::new(0), misses: AtomicUsize::new(0) }
+    }
+}
+
+/// An event recorded by the statistics of a pool.
+enum Event {
+    Hit,
+    Miss,
+}
 
-impl<T: fmt::Debug + Send + 'static> fmt::Debug for Pool<T> {
+// The values themselves aren't formatted: they belong to the threads that use
+// the pool, and may be mutated by them at any time.
+impl<T: Send, F> fmt::Debug for Pool<T, F> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "Pool({:?})", self.owner_val)
+        f.debug_struct("Pool")
+            .field("owned", &(self.owner.id.load(Relaxed) != 0))
+            .field("len", &self.len())
+            .finish()
     }
 }
 
-impl<T: Send> Pool<T> {
+#[cfg(feature = "serde")]
+impl<T: serde::Serialize + Send + Sync, F> serde::Serialize for Pool<T, F> {
+    /// Serializes the owner's value, as returned by `peek_owner`.
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        self.peek_owner().serialize(serializer)
+    }
+}
+
+impl<'a, T: Send> Pool<T, scoped::ScopedCreateFn<'a, T>> {
+    /// Create a new memory pool with the given initialization function.
+    ///
+    /// The initialization function may borrow from its environment, in which
+    /// case the pool can't outlive the data it borrows. See the `scoped`
+    /// module.
+    pub fn new(
+        create: scoped::ScopedCreateFn<'a, T>,
+    ) -> Pool<T, scoped::ScopedCreateFn<'a, T>> {
+        Pool::with_fn(create)
+    }
+
+    /// Create a new memory pool whose owner's value is created lazily.
+    ///
+    /// `new` creates the owner's value right away, so that the cost is paid
+    /// up front. This instead creates it on the owner's first call to `get`,
+    /// like the values of every other thread, which saves the work if the
+    /// pool is never used. After the first call, the owner's fast path is
+    /// just as fast. (`new_const` is the same, for initialization functions
+    /// that aren't boxed.)
+    pub fn new_lazy(
+        create: scoped::ScopedCreateFn<'a, T>,
+    ) -> Pool<T, scoped::ScopedCreateFn<'a, T>> {
+        Pool::from_parts(create, Owner::new(None))
+    }
+
+    /// Create a new memory pool whose owner's value is `owner`.
+    ///
+    /// This is like `new`, except that the owner's value is given rather than
+    /// created, which avoids calling `create` when a value has already been
+    /// built. The pool still has no owner until the first call to `get`, and
+    /// whichever thread makes it gets `owner`. `create` is only called for
+    /// the other threads.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::with_owner(Box::new(|| vec![0; 16]), vec![1, 2, 3]);
+    /// assert_eq!(&[1, 2, 3], &pool.get()[..]);
+    /// # }
+    /// ```
+    pub fn with_owner(
+        create: scoped::ScopedCreateFn<'a, T>,
+        owner: T,
+    ) -> Pool<T, scoped::ScopedCreateFn<'a, T>> {
+        Pool::from_parts(create, Owner::new(Some(owner)))
+    }
+
+    /// Create a new memory pool without an owner.
+    ///
+    /// Normally, the first thread to call `get` becomes the owner, and gets a
+    /// fast path that skips the map. When no thread uses the pool more than
+    /// the others, that isn't worth creating the owner's value up front and
+    /// claiming the owner slot for. In a pool created by this constructor, no
+    /// thread becomes the owner by calling `get`, so every thread gets its
+    /// value from the map (and its cache of the last value it got), and
+    /// `adopt` always returns false.
+    ///
+    /// A thread can still become the owner by calling `claim_ownership`. So
+    /// this is also how to designate the owner explicitly: threads that touch
+    /// the pool early, e.g., to warm it up, can't take the fast path away
+    /// from the thread that later claims it.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new_symmetric(Box::new(|| vec![0u8; 16]));
+    /// assert_eq!(16, pool.get().len());
+    /// assert!(!pool.is_owner());
+    /// # }
+    /// ```
+    pub fn new_symmetric(
+        create: scoped::ScopedCreateFn<'a, T>,
+    ) -> Pool<T, scoped::ScopedCreateFn<'a, T>> {
+        let mut pool = Pool::from_parts(create, Owner::new(None));
+        pool.symmetric = true;
+        pool
+    }
+
+    /// Create a new memory pool that keeps every value in a box, including
+    /// the owner's.
+    ///
+    /// The owner's value is normally stored inline, which saves a pointer
+    /// indirection on the fast path, but makes the pool at least as big as a
+    /// value. For large values, such as `[u8; 4096]`, this keeps the pool
+    /// itself small, so it's cheap to move, e.g., into an `Arc`. Values are
+    /// retrieved with `get_dyn`, which dereferences the box.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use std::mem::size_of_val;
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new_boxed(Box::new(|| [0u8; 4096]));
+    /// assert!(size_of_val(&pool) < 4096);
+    /// assert_eq!(4096, pool.get_dyn().len());
+    /// # }
+    /// ```
+    pub fn new_boxed(
+        create: scoped::ScopedCreateFn<'a, T>,
+    ) -> Pool<Box<T>, scoped::ScopedCreateFn<'a, Box<T>>> where T: 'a {
+        Pool::new(Box::new(move || Box::new(create())))
+    }
+
+    /// Create a new memory pool that already holds the given values, each for
+    /// the thread with the given id.
+    ///
+    /// This simulates a pool that has been warmed up, e.g., for testing the
+    /// slow path deterministically with `get_with_id`. Values for id `0`,
+    /// which is never a thread's id, are dropped, and if an id is given more
+    /// than once, then only its first value is kept. Like `new`, this creates
+    /// the owner's value right away. The owner is the first thread to call
+    /// `get`, even if it was given a value here, in which case that value is
+    /// never returned by `get` again.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::from_values(Box::new(|| 0), vec![(5, 50), (6, 60)]);
+    /// assert_eq!(&0, unsafe { pool.get_with_id(1) });
+    /// assert_eq!(&60, unsafe { pool.get_with_id(6) });
+    /// ```
+    pub fn from_values<I>(
+        create: scoped::ScopedCreateFn<'a, T>,
+        values: I,
+    ) -> Pool<T, scoped::ScopedCreateFn<'a, T>>
+            where I: IntoIterator<Item = (usize, T)> {
+        let mut pool = Pool::new(create);
+        let gen = pool.owner.generation.load(Relaxed);
+        for (id, value) in values {
+            if id != 0 {
+                let shard = pool.global[id % SHARDS].get_mut().unwrap();
+                drop(shard.insert(id, gen, value).1);
+            }
+        }
+        pool
+    }
+
+    /// Create a new memory pool whose initialization function is given a
+    /// reference to `state`.
+    ///
+    /// The state is kept alive by the pool. This is convenient when every
+    /// value is built from the same read-only resource. Values can't borrow
+    /// from the state itself, but if the state is an `Arc`, then each value
+    /// can hold a clone of it.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use std::sync::Arc;
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let words = Arc::new(vec!["foo", "bar"]);
+    /// let pool = Pool::new_with_state(words, Box::new(|words| {
+    ///     (Arc::clone(words), String::new())
+    /// }));
+    /// assert_eq!(2, pool.get().0.len());
+    /// # }
+    /// ```
+    pub fn new_with_state<S: Send + Sync + 'a>(
+        state: S,
+        create: Box<dyn Fn(&S) -> T + Send + Sync + 'a>,
+    ) -> Pool<T, scoped::ScopedCreateFn<'a, T>> where T: 'a {
+        Pool::new(Box::new(move || create(&state)))
+    }
+
+    /// Create a new memory pool with an initialization function that may
+    /// mutate its own state.
+    ///
+    /// Calls to the initialization function are serialized by a lock, which
+    /// is only taken when a new value is created, so getting an existing value
+    /// is just as fast as with `new`. The initialization function must not
+    /// use the pool itself, since that would deadlock.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let mut next = 0;
+    /// let pool = Pool::new_mut(Box::new(move || { next += 1; next }));
+    /// assert_eq!(1, *pool.get());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn new_mut(
+        create: Box<dyn FnMut() -> T + Send + 'a>,
+    ) -> Pool<T, scoped::ScopedCreateFn<'a, T>> where T: 'a {
+        let create = std::sync::Mutex::new(create);
+        Pool::new(Box::new(move || {
+            // If a previous call panicked, then the lock is poisoned. But the
+            // pool should remain usable, just like with `new`.
+            let mut create =
+                create.lock().unwrap_or_else(|err| err.into_inner());
+            create()
+        }))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Send + 'static> Pool<T> {
+    /// Create a new memory pool whose values are all created on a dedicated
+    /// thread.
+    ///
+    /// This is for values that must be created on a particular thread, but
+    /// can then be used on any thread. A thread is spawned that runs `create`
+    /// on behalf of the pool, and every thread that needs a new value sends
+    /// it a request and blocks until the value is sent back. So every miss
+    /// pays for two channel round trips and a context switch, on top of
+    /// creating the value. Getting an existing value is just as fast as with
+    /// `new`. The thread exits when the pool is dropped.
+    ///
+    /// Like `new`, this creates the owner's value right away. If `create`
+    /// panics, then the creator thread dies, and every call that needs a new
+    /// value panics from then on (including this one, if it's the first).
+    ///
+    /// ```rust
+    /// use std::thread;
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::with_creator_thread(|| thread::current().id());
+    /// assert_ne!(thread::current().id(), *pool.get());
+    /// ```
+    pub fn with_creator_thread<C>(mut create: C) -> Pool<T>
+            where C: FnMut() -> T + Send + 'static {
+        use std::sync::mpsc;
+
+        let (requests, rx) = mpsc::channel::<mpsc::SyncSender<T>>();
+        std::thread::Builder::new()
+            .name("mempool-creator".into())
+            .spawn(move || {
+                for reply in rx {
+                    // The requester may have given up, e.g., by panicking.
+                    let _ = reply.send(create());
+                }
+            })
+            .expect("failed to spawn the creator thread");
+        Pool::new(Box::new(move || {
+            let (reply, value) = mpsc::sync_channel(1);
+            requests.send(reply).expect("the creator thread panicked");
+            value.recv().expect("the creator thread panicked")
+        }))
+    }
+}
+
+impl<T: Default + Send + 'static> Default for Pool<T> {
+    /// Create a new memory pool that creates values with `T::default`.
+    ///
+    /// The initialization function is the zero-sized `T::default` itself, so
+    /// boxing it doesn't allocate.
+    fn default() -> Pool<T> {
+        Pool::new(Box::new(T::default))
+    }
+}
+
+impl<T, F> Pool<T, F> where T: Send, F: Fn() -> T + Send + Sync {
     /// Create a new memory pool with the given initialization function.
-    pub fn new(create: CreateFn<T>) -> Pool<T> {
+    ///
+    /// Unlike `new`, the closure is stored inline in the pool and called
+    /// without dynamic dispatch, which avoids an allocation and an indirect
+    /// call whenever a new value is created.
+    pub fn with_fn(create: F) -> Pool<T, F> {
         let owner_val = (create)();
+        Pool::from_parts(create, Owner::new(Some(owner_val)))
+    }
+
+    /// Create a new memory pool with the given initialization and reset
+    /// functions.
+    ///
+    /// The reset function is called on a value every time it is handed out
+    /// by `get_mut`, and every time a value is reused by `checkout`. For
+    /// example, a pool of scratch buffers can use `|v| v.clear()` to reuse
+    /// each buffer's capacity without carrying over its contents.
+    ///
+    /// The reset function is *not* called by `get`, since references returned
+    /// by previous calls to `get` may still be alive, and mutating the value
+    /// out from under them would be undefined behavior.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let reset = Box::new(|v: &mut Vec<u8>| v.clear());
+    /// let pool = Pool::with_reset(Vec::new, reset);
+    /// unsafe { pool.get_mut().extend_from_slice(b"stale") };
+    /// assert!(unsafe { pool.get_mut() }.is_empty());
+    /// # }
+    /// ```
+    pub fn with_reset(create: F, reset: ResetFn<T>) -> Pool<T, F> {
+        let mut pool = Pool::with_fn(create);
+        pool.reset = Some(Arc::from(reset));
+        pool
+    }
+
+    /// Create a new memory pool with the given initialization function and
+    /// finalizer.
+    ///
+    /// Every value the pool discards is passed to the finalizer instead of
+    /// being dropped, so that it can do work that doesn't belong in the
+    /// value's `Drop` impl, e.g., reporting statistics the value collected.
+    /// This includes the owner's value and the values of every other thread
+    /// when the pool is dropped or cleared, idle values discarded by
+    /// `checkout`, and values removed by `cleanup_on_thread_exit`. Values
+    /// returned by `into_inner` or `remove_current_thread` belong to the
+    /// caller, so they aren't finalized.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use std::sync::Arc;
+    /// use std::sync::atomic::{AtomicUsize, Ordering};
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let total = Arc::new(AtomicUsize::new(0));
+    /// let sink = Arc::clone(&total);
+    /// let pool = Pool::with_finalizer(|| 5, Box::new(move |n| {
+    ///     sink.fetch_add(n, Ordering::SeqCst);
+    /// }));
+    /// pool.get();
+    /// drop(pool);
+    /// assert_eq!(5, total.load(Ordering::SeqCst));
+    /// # }
+    /// ```
+    pub fn with_finalizer(create: F, finalize: FinalizeFn<T>) -> Pool<T, F> {
+        let mut pool = Pool::with_fn(create);
+        pool.finalize = Some(Arc::from(finalize));
+        pool
+    }
+
+    /// Create a new memory pool that keeps at most `max` idle values for
+    /// `checkout`.
+    ///
+    /// When a `PoolGuard` is dropped while `max` values are already idle,
+    /// its value is dropped instead of being returned to the pool. This
+    /// bounds the memory held for bursts of `checkout` calls.
+    ///
+    /// The values cached by `get` aren't affected, since references to them
+    /// may be alive at any time, so they can't be evicted. (The values of
+    /// exited threads can be dropped with `cleanup_on_thread_exit`.)
+    pub fn with_max_idle(create: F, max: usize) -> Pool<T, F> {
+        let mut pool = Pool::with_fn(create);
+        pool.max_idle = max;
+        pool
+    }
+
+    /// Create a new memory pool that keeps at most `max` idle values for
+    /// `checkout` for each thread.
+    ///
+    /// This is like `with_max_idle`, except that the bound applies to each
+    /// thread's idle values separately, so a thread that briefly needed many
+    /// values doesn't keep them all, and can't crowd out the idle values of
+    /// other threads.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::with_max_idle_per_thread(|| vec![0u8; 1024], 2);
+    /// // Only two of these buffers are kept when they're dropped.
+    /// drop(pool.checkout_many(10));
+    /// # }
+    /// ```
+    pub fn with_max_idle_per_thread(create: F, max: usize) -> Pool<T, F> {
+        let mut pool = Pool::with_fn(create);
+        pool.max_idle_per_thread = max;
+        pool
+    }
+
+    /// Create a new memory pool with room for the values of `n` threads.
+    ///
+    /// The maps holding the values of non-owner threads grow as threads
+    /// start using the pool, and growing a map means rehashing it while its
+    /// shard is locked. If the number of threads is known in advance, then
+    /// reserving room for them up front keeps that latency off the slow path
+    /// during the initial burst. (The values themselves are stored in chunks
+    /// that double in size, so they only take a logarithmic number of
+    /// allocations either way.)
+    ///
+    /// For example, a pool used by the workers of a thread pool like rayon's
+    /// can reserve room for exactly as many threads as there are workers.
+    /// Thread ids are assigned smallest first, so the ids of a fixed set of
+    /// workers stay dense.
+    ///
+    /// With the `numa` feature, a node's threads only use the node's own
+    /// shards, and how the threads are spread over the nodes isn't known up
+    /// front. So every node's shards get room for all `n` threads, which
+    /// reserves more than `n` in total on a machine with several nodes.
+    #[cfg(feature = "std")]
+    pub fn with_thread_capacity(create: F, n: usize) -> Pool<T, F> {
+        let mut pool = Pool::with_fn(create);
+        // Thread ids are dense, so they're spread evenly over the shards
+        // they can land in.
+        #[cfg(not(feature = "numa"))]
+        let shards = SHARDS;
+        #[cfg(feature = "numa")]
+        let shards = SHARDS / numa::nodes();
+        let per_shard = n.div_ceil(shards);
+        for shard in &mut pool.global {
+            shard.get_mut().unwrap().reserve(per_shard);
+        }
+        pool
+    }
+
+    /// Create a new memory pool whose owner's value is created lazily.
+    ///
+    /// Unlike `with_fn`, no value is created until the first call to `get`.
+    /// This makes it possible for this constructor to be a `const fn`, so a
+    /// pool can be declared as a `static`. Since a boxed closure can't be
+    /// created in a constant, the initialization function is usually a
+    /// function pointer:
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// static POOL: Pool<Vec<u8>, fn() -> Vec<u8>> = Pool::new_const(Vec::new);
+    /// assert!(POOL.get().is_empty());
+    /// # }
+    /// ```
+    #[cfg(not(feature = "loom"))]
+    pub const fn new_const(create: F) -> Pool<T, F> {
         Pool {
-            create: create,
-            owner: AtomicUsize::new(0),
-            owner_val: owner_val,
-            global: Mutex::new(HashMap::new()),
+            create,
+            reset: None,
+            finalize: None,
+            uid: AtomicUsize::new(0),
+            owner: Owner {
+                id: AtomicUsize::new(0),
+                generation: AtomicUsize::new(1),
+                gen: AtomicUsize::new(0),
+                val: UnsafeCell::new(MaybeUninit::uninit()),
+            },
+            #[cfg(feature = "std")]
+            owner_alive: std::sync::Mutex::new(None),
+            #[cfg(feature = "std")]
+            inited: std::sync::Mutex::new(new_map()),
+            global: [const { Mutex::new(Shard::new()) }; SHARDS],
+            free: Mutex::new(Free::new()),
+            max_idle: usize::MAX,
+            max_idle_per_thread: usize::MAX,
+            symmetric: false,
+            #[cfg(feature = "stats")]
+            stats: Stats {
+                hits: AtomicUsize::new(0),
+                misses: AtomicUsize::new(0),
+            },
+            spare: Mutex::new(Vec::new()),
         }
     }
 
+    /// Create a new memory pool whose values are derived from the values of
+    /// this pool.
+    ///
+    /// The initialization function of the new pool creates a value with this
+    /// pool's initialization function, passes it to `f` and drops it. The
+    /// new pool is otherwise independent: it has its own owner and caches
+    /// its own values, and never hands out values cached by this pool. Like
+    /// `Pool::new`, the new pool's owner's value is created immediately.
+    ///
+    /// The new pool owns a clone of this pool's initialization function, so
+    /// it doesn't borrow this pool and may outlive it. This requires an
+    /// initialization function that can be cloned, e.g., a function pointer
+    /// or a closure passed to `with_fn`, so a boxed `CreateFn` won't do.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::with_fn(|| vec![1u8, 2, 3]);
+    /// let lens = pool.map(|v| v.len());
+    /// drop(pool);
+    /// assert_eq!(3, *lens.get());
+    /// # }
+    /// ```
+    pub fn map<U, G>(&self, f: G) -> Pool<U>
+            where F: Clone + Send + Sync + 'static,
+                  U: Send,
+                  G: Fn(&T) -> U + Send + Sync + 'static {
+        let create = self.create.clone();
+        Pool::new(Box::new(move || f(&create())))
+    }
+
     /// Get a reference to a new value from the pool. The underlying value may
     /// be reused in subsequent calls to `get`.
     ///
@@ -78,96 +1200,3741 @@ impl<T: Send> Pool<T> {
     // which saves quite a few cycles. (And seems appropriate, since the whole
     // point here is to reduce overhead.) It's good for about 3x improvement
     // in the mempool_get_put_tls benchmark.
+    #[cfg(feature = "std")]
     #[inline(always)]
     pub fn get(&self) -> &T {
-        let id = THREAD_ID.with(|id| *id);
-        let owner = self.owner.load(Relaxed);
-        // If the owner has already been assigned and this thread is the owner,
-        // then just return a reference to the owner's cache.
-        if owner == id {
-            return &self.owner_val;
+        let id = thread_id::current();
+        if let Some(val) = self.owner_get(id) {
+            return unsafe { &*val };
         }
-        self.get_slow(owner, id)
+        unsafe { &*unwrap(self.get_cached(id, || self.new_value())) }
     }
 
-    #[cold]
-    fn get_slow(&self, owner: usize, thread_id: usize) -> &T {
-        if owner == 0 {
-            if self.owner.compare_and_swap(0, thread_id, Relaxed) == 0 {
-                return &self.owner_val;
-            }
+    /// Get a reference to this thread's value, returning an error instead of
+    /// panicking if the pool fails.
+    ///
+    /// This is like `get`, except that if the initialization function panics,
+    /// then `PoolError::CreateFailed` is returned, and if a lock guarding the
+    /// pool has been poisoned, then `PoolError::Poisoned` is returned. In
+    /// both cases nothing is cached, and the pool remains usable. Panics are
+    /// caught with `catch_unwind`, so this only helps if panics unwind.
+    #[cfg(feature = "std")]
+    pub fn get_checked(&self) -> Result<&T, PoolError> {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let id = thread_id::current();
+        if let Some(val) = self.owner_get(id) {
+            return Ok(unsafe { &*val });
         }
-        let mut global = self.global.lock().unwrap();
-        match global.entry(thread_id) {
-            Entry::Occupied(ref e) => {
-                let p: *const T = &**e.get();
-                unsafe { &*p }
-            }
-            Entry::Vacant(e) => {
-                let t = Box::new((self.create)());
-                let p: *const T = &*t;
-                e.insert(t);
-                unsafe { &*p }
-            }
+        // Nothing is cached until a value has been created, so the pool is
+        // left as it was by a panic in either closure.
+        let create = || {
+            panic::catch_unwind(AssertUnwindSafe(|| unwrap(self.new_value())))
+                .map_err(|_| PoolError::CreateFailed)
+        };
+        // Other than the initialization function, only the locks of the
+        // pool can panic, when they've been poisoned.
+        let val = AssertUnwindSafe(|| self.get_cached(id, create));
+        match panic::catch_unwind(val) {
+            Ok(val) => val.map(|val| unsafe { &*val }),
+            Err(_) => Err(PoolError::Poisoned),
         }
     }
-}
-
-#[cfg(test)]
-#[cfg(feature = "nightly")]
-mod bench;
-
-#[cfg(test)]
-mod tests {
-    use std::cell::RefCell;
-    use std::sync::Arc;
-    use std::sync::atomic::AtomicUsize;
-    use std::sync::atomic::Ordering::SeqCst;
-    use std::thread;
 
-    use super::{CreateFn, Pool};
+    /// Get a reference to the value for the thread identified by `id`.
+    ///
+    /// This is like `get`, except the caller identifies the current thread
+    /// instead of the pool. This is the only way to get a value when the
+    /// `std` feature is disabled, since there are no thread locals to
+    /// identify threads with. The first id used with the pool becomes its
+    /// owner and gets the fast path.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `id` is `0`, which is reserved.
+    ///
+    /// # Safety
+    ///
+    /// Values aren't synchronized, so the caller must guarantee that no two
+    /// threads use the same id at the same time. If the `std` feature is
+    /// enabled, then the ids used by `get` must not be mixed with ids chosen
+    /// by the caller, except that the current thread may always use the id
+    /// returned by `current_thread_id`.
+    #[inline(always)]
+    pub unsafe fn get_with_id(&self, id: usize) -> &T {
+        assert!(id != 0, "thread id 0 is reserved");
+        self.get_by_id(id)
+    }
+
+    /// Get a reference to the value for the thread identified by `source`.
+    ///
+    /// This is like `get_with_id`, except the id comes from a
+    /// `ThreadIdSource`, whose implementation upholds the safety contract of
+    /// `get_with_id`. This makes it possible to use a pool with a different
+    /// notion of "thread", e.g., green threads, or a deterministic sequence
+    /// of ids in tests. `get` is unaffected, and keeps using the ids of OS
+    /// threads.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `source` returns `0`, which is reserved.
+    #[inline(always)]
+    pub fn get_from<S: ThreadIdSource>(&self, source: &S) -> &T {
+        let id = source.current_id();
+        assert!(id != 0, "thread id 0 is reserved");
+        unsafe { self.get_by_id(id) }
+    }
+
+    #[inline(always)]
+    unsafe fn get_by_id(&self, id: usize) -> &T {
+        // If the owner has already been assigned and this thread is the owner,
+        // then just return a reference to the owner's cache.
+        if let Some(val) = self.owner_get(id) {
+            return &*val;
+        }
+        &*unwrap(self.get_slow(id, || self.new_value()))
+    }
+
+    /// Get a mutable reference to this thread's value from the pool.
+    ///
+    /// This is like `get`, except the value can be mutated in place without
+    /// wrapping it in a `RefCell`. If a value for this thread does not exist,
+    /// then it is created with the initialization function.
+    ///
+    /// # Safety
+    ///
+    /// Each thread has exclusive access to its own value, so the only way to
+    /// alias the returned reference is from the calling thread itself. Since
+    /// this method takes `&self`, the borrow checker does *not* prevent that:
+    /// two calls to `get_mut`, or a call to `get_mut` while a reference
+    /// returned by `get` is alive, would produce aliasing references.
+    ///
+    /// The caller must therefore guarantee that, for as long as the returned
+    /// reference is used, no other reference to the calling thread's value
+    /// obtained from this pool is alive. If the calling thread took over the
+    /// owner's value with `adopt`, then this includes references obtained by
+    /// the exited owner. For the owner, it also includes references returned
+    /// by `peek_owner` on any thread. And no call to `total_allocated` may be
+    /// running on any thread, since it reads every value.
+    // Handing out `&mut T` from `&self` is exactly what the safety contract
+    // above is about.
+    #[allow(clippy::mut_from_ref)]
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub unsafe fn get_mut(&self) -> &mut T {
+        let id = thread_id::current();
+        let val = match self.owner_get(id) {
+            Some(val) => &mut *val,
+            None => &mut *unwrap(self.get_cached(id, || self.new_value())),
+        };
+        if let Some(ref reset) = self.reset {
+            reset(val);
+        }
+        val
+    }
+
+    /// Get a reference to the current thread's value, if it already has one.
+    ///
+    /// Unlike `get`, this never calls the initialization function, so it
+    /// never allocates. Combined with `prewarm`, this makes it possible to
+    /// check that a hot path only ever uses values that were created ahead
+    /// of time. For the owner, it's as cheap as `get`. Other threads may
+    /// need to take a lock.
+    #[cfg(feature = "std")]
+    pub fn get_existing(&self) -> Option<&T> {
+        let id = thread_id::current();
+        if let Some(val) = self.owner.get(id) {
+            return Some(unsafe { &*val });
+        }
+        let uid = self.uid.load(Acquire);
+        if uid != 0 {
+            if let Some(val) = thread_id::cached(uid, id) {
+                return Some(unsafe { &*val.cast() });
+            }
+        }
+        let gen = self.owner.generation.load(Relaxed);
+        if self.owner.id.load(Acquire) == id
+            && self.owner.gen.load(Relaxed) == 0
+        {
+            return None;
+        }
+        let val = self.shard(id).lock().unwrap().get(id, gen)?;
+        thread_id::used(id);
+        Some(unsafe { &*val })
+    }
+
+    /// Remove the current thread's value from the pool and return it.
+    ///
+    /// The next call to `get` on this thread creates a new value, e.g., to
+    /// replace a value that has grown too large. If the current thread has
+    /// no value, then `None` is returned. The current thread stays the owner
+    /// if it was the owner. Values made stale by `bump_generation` aren't
+    /// removed.
+    ///
+    /// # Safety
+    ///
+    /// The value is moved out of the pool, so no reference to it may be
+    /// alive. The caller must guarantee that no reference to the current
+    /// thread's value obtained from this pool is alive, which includes
+    /// references obtained by the exited owner if the calling thread took
+    /// over its value with `adopt`. If the current thread is the owner, then
+    /// no reference returned by `peek_owner` may be alive, and no other
+    /// thread may call `peek_owner` during this call.
+    #[cfg(feature = "std")]
+    pub unsafe fn remove_current_thread(&self) -> Option<T> {
+        #[cfg(feature = "debug-tracking")]
+        self.assert_not_borrowed("remove_current_thread");
+        self.remove_thread(thread_id::current())
+    }
+
+    /// Replace the current thread's value with `new`, and return the old
+    /// value.
+    ///
+    /// This is useful for, e.g., replacing a buffer with a bigger one. If
+    /// the current thread has no value, then `new` becomes its value and
+    /// `None` is returned. The value is replaced in place, so the current
+    /// thread stays the owner if it was the owner. Otherwise, it doesn't
+    /// become the owner, even if no thread is.
+    ///
+    /// # Safety
+    ///
+    /// The old value is moved out of the pool, so the caller must uphold the
+    /// same contract as for `remove_current_thread`: no reference to the
+    /// current thread's value obtained from this pool may be alive.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new(Box::new(|| Vec::<u8>::with_capacity(16)));
+    /// pool.get();
+    /// let old = unsafe { pool.swap_current_thread(Vec::with_capacity(64)) };
+    /// assert_eq!(Some(16), old.map(|v| v.capacity()));
+    /// assert!(pool.get().capacity() >= 64);
+    /// ```
+    #[cfg(feature = "std")]
+    pub unsafe fn swap_current_thread(&self, new: T) -> Option<T> {
+        #[cfg(feature = "debug-tracking")]
+        self.assert_not_borrowed("swap_current_thread");
+        let id = thread_id::current();
+        self.forget_init(id);
+        if let Some(val) = self.owner.get(id) {
+            return Some(ptr::replace(val, new));
+        }
+        // A thread that isn't the owner keeps its value in the map, even if
+        // the owner slot is free, since claiming it might hand the thread a
+        // value created up front instead of `new`. `get_slow` then finds the
+        // value in the map before it tries to claim the slot.
+        thread_id::used(id);
+        let gen = self.owner.generation.load(Relaxed);
+        let mut shard = self.shard(id).lock().unwrap();
+        match shard.get(id, gen) {
+            // The old value is replaced in place, so the thread's cache
+            // still points at the right slot.
+            Some(val) => Some(ptr::replace(val, new)),
+            None => {
+                shard.insert(id, gen, new);
+                None
+            }
+        }
+    }
+
+    /// Get a reference to this thread's value from the pool, wrapped in a
+    /// `TrackedRef`.
+    ///
+    /// This is like `get`, except that with the `debug-tracking` feature,
+    /// the reference is counted until the `TrackedRef` is dropped, and
+    /// `remove_current_thread` and `swap_current_thread` panic if they're
+    /// called by this thread while the count is nonzero. This catches
+    /// references that are held for longer than intended, before the value
+    /// they point to is moved. (`clear` requires exclusive access to the
+    /// pool, so the borrow checker already rules out references into it.)
+    /// Without the feature, nothing is counted, and a `TrackedRef` is just a
+    /// reference, so there's no overhead in release builds.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new(Box::new(|| vec![1, 2, 3]));
+    /// let value = pool.get_tracked();
+    /// assert_eq!(3, value.len());
+    /// ```
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub fn get_tracked(&self) -> TrackedRef<'_, T> {
+        let value = self.get();
+        #[cfg(feature = "debug-tracking")]
+        thread_id::borrow(self as *const Self as usize);
+        TrackedRef {
+            value,
+            #[cfg(feature = "debug-tracking")]
+            pool: self as *const Self as usize,
+            _not_send: PhantomData,
+        }
+    }
+
+    /// Get a clonable handle to this thread's value from a shared pool.
+    ///
+    /// This is like `get`, except that the handle keeps the pool alive
+    /// instead of borrowing it, so it can be passed around the current
+    /// thread's call stack without threading a lifetime through. Cloning the
+    /// handle only bumps a reference count that isn't shared with other
+    /// threads, and the handle can't be sent to another thread.
+    ///
+    /// ```rust
+    /// use std::sync::Arc;
+    ///
+    /// use mempool::{Pool, PoolRc};
+    ///
+    /// fn len(buf: PoolRc<Vec<u8>>) -> usize {
+    ///     buf.len()
+    /// }
+    ///
+    /// let pool = Arc::new(Pool::new(Box::new(|| vec![0; 16])));
+    /// let buf = pool.get_rc();
+    /// assert_eq!(16, len(buf.clone()));
+    /// drop(pool);
+    /// assert_eq!(16, buf.len());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn get_rc(self: &Arc<Self>) -> PoolRc<T, F> {
+        let value = self.get() as *const T;
+        PoolRc { inner: Rc::new(RcInner { pool: self.clone(), value }) }
+    }
+
+    /// Get a reference to the current thread's value without ever blocking.
+    ///
+    /// This is like `get_existing`, except that it never waits for a lock:
+    /// if the lock guarding this thread's value is held by another thread,
+    /// e.g., one that's adding its own value, then `None` is returned. It
+    /// also never creates a value, since that would allocate. This makes it
+    /// suitable for threads with real-time constraints, which can call
+    /// `prewarm` before they start and fall back to some other value when
+    /// this returns `None`.
+    ///
+    /// Note that this may return `None` under contention even if the current
+    /// thread has a value. The owner never takes a lock, so for the owner
+    /// (and for a thread whose value is cached), this only returns `None`
+    /// if it has no value.
+    #[cfg(feature = "std")]
+    pub fn get_nonblocking(&self) -> Option<&T> {
+        let id = thread_id::current();
+        if let Some(val) = self.owner.get(id) {
+            return Some(unsafe { &*val });
+        }
+        let uid = self.uid.load(Acquire);
+        if uid != 0 {
+            if let Some(val) = thread_id::cached(uid, id) {
+                return Some(unsafe { &*val.cast() });
+            }
+        }
+        let gen = self.owner.generation.load(Relaxed);
+        if self.owner.id.load(Acquire) == id
+            && self.owner.gen.load(Relaxed) == 0
+        {
+            return None;
+        }
+        let val = self.shard(id).try_lock().ok()?.get(id, gen)?;
+        thread_id::used(id);
+        Some(unsafe { &*val })
+    }
+
+    /// Returns true if the current thread is the owner of this pool.
+    ///
+    /// The owner is the first thread to call `get` (or to `adopt` the pool),
+    /// and is the only thread that uses the fast path. This is a single
+    /// atomic load, so it's cheap enough to assert that a hot thread really
+    /// did become the owner.
+    #[cfg(feature = "std")]
+    pub fn is_owner(&self) -> bool {
+        self.owner.id.load(Relaxed) == thread_id::current()
+    }
+
+    /// Returns true if the current thread already has a value cached by
+    /// `get`.
+    ///
+    /// Like `get_existing`, this never creates a value.
+    #[cfg(feature = "std")]
+    pub fn contains_current_thread(&self) -> bool {
+        self.get_existing().is_some()
+    }
+
+    /// Ensure that the current thread's value has been created.
+    ///
+    /// Values are created lazily, the first time each thread calls `get`. A
+    /// latency sensitive program can move that cost out of its hot path by
+    /// having each worker thread call `prewarm` when it starts.
+    #[cfg(feature = "std")]
+    pub fn prewarm(&self) {
+        self.get();
+    }
+
+    /// Create `n` values up front, to be claimed later by threads that don't
+    /// have a value yet.
+    ///
+    /// A thread that needs a new value, whether in `get` or in `checkout`,
+    /// takes one of these spare values instead of calling the initialization
+    /// function. This is useful when the number of threads that will use the
+    /// pool is known ahead of time, but the threads themselves don't exist
+    /// yet. Spare values aren't counted by `len`.
+    pub fn fill(&self, n: usize) {
+        // The values are created without holding the lock, so that other
+        // threads can claim spare values in the meantime.
+        let values: Vec<T> = (0..n).map(|_| (self.create)()).collect();
+        self.spare.lock().unwrap().extend(values);
+    }
+
+    /// Make the current thread the owner of this pool if the owner has exited.
+    ///
+    /// The first thread to call `get` becomes the owner, and only the owner
+    /// gets the fast path. If that isn't the thread that uses the pool the
+    /// most, then once the owner exits, the hot thread can take over the fast
+    /// path with `adopt`. The current thread inherits the owner's value. A
+    /// value previously created for the current thread stays in the pool,
+    /// but is no longer returned by `get`.
+    ///
+    /// This returns true if the current thread is the owner when it returns.
+    /// An owner that is still alive can't be displaced, since it may be using
+    /// its value. Neither can an owner whose id didn't come from the thread
+    /// that used it, e.g., one given to `get_with_id`, since there is no way
+    /// to tell whether it has exited.
+    ///
+    /// # Safety
+    ///
+    /// An owner counts as exited once its thread has run its exit callbacks,
+    /// which happens while its thread local destructors run. A destructor
+    /// that runs after that may still use a reference to the owner's value
+    /// that it got earlier, at the same time as the current thread. The
+    /// caller must guarantee that this doesn't happen, e.g., by having joined
+    /// the owner's thread. If `T: Sync`, then the caller must also guarantee
+    /// that no reference to the owner's value obtained by the exited owner
+    /// is alive on another thread, if the current thread mutates the value
+    /// through `get_mut` or moves it out with `remove_current_thread`.
+    #[cfg(feature = "std")]
+    pub unsafe fn adopt(&self) -> bool {
+        if self.symmetric {
+            return false;
+        }
+        let id = thread_id::current();
+        let owner = self.owner.id.load(Acquire);
+        if owner == id {
+            return true;
+        }
+        // The flag is checked with `Acquire`, which orders the exited
+        // owner's accesses to its value before ours. It belongs to the
+        // owner that was read above, since the id of a thread that was
+        // handed a value is never given to another thread.
+        let exited = owner == 0 || match *self.owner_alive.lock().recover() {
+            Some((o, ref alive)) => o == owner && !alive.load(Acquire),
+            None => false,
+        };
+        if !exited {
+            return false;
+        }
+        // The id must be marked as used before the slot holds it, so that
+        // it's never given to another thread while this thread owns it.
+        thread_id::used(id);
+        if self.owner.id.compare_exchange(owner, id, AcqRel, Acquire).is_err()
+        {
+            return false;
+        }
+        self.owned(id);
+        true
+    }
+
+    /// Make the current thread the owner of this pool if no thread is.
+    ///
+    /// This returns true if the current thread is the owner when it returns.
+    /// Unlike the claim made by `get`, this also works in a pool created by
+    /// `new_symmetric`, where it's the only way to become the owner. If the
+    /// current thread already has a value in the map, then it gets the
+    /// owner's value from now on instead, just like with `adopt`.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new_symmetric(Box::new(|| vec![0u8; 16]));
+    /// std::thread::scope(|s| {
+    ///     // A warmup thread doesn't claim the owner slot...
+    ///     s.spawn(|| pool.get().len());
+    /// });
+    /// // ...so the thread that wants the fast path can.
+    /// assert!(pool.claim_ownership());
+    /// assert!(pool.is_owner());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn claim_ownership(&self) -> bool {
+        let id = thread_id::current();
+        thread_id::used(id);
+        // The slot is claimed with the same orderings as in `get_slow`.
+        match self.owner.id.compare_exchange(0, id, AcqRel, Acquire) {
+            Ok(_) => {
+                self.owned(id);
+                true
+            }
+            Err(owner) => owner == id,
+        }
+    }
+
+    /// Finishes making the current thread, `id`, the owner, once it has
+    /// claimed the owner slot with `claim_ownership` or `adopt`.
+    ///
+    /// Its value from the map is no longer returned by `get`, so it's
+    /// forgotten by its cache. And the owner's value is created if it
+    /// hasn't been, so that it exists whenever `is_owner` returns true.
+    #[cfg(feature = "std")]
+    fn owned(&self, id: usize) {
+        self.owned_by(id);
+        let uid = self.uid.load(Acquire);
+        if uid != 0 {
+            thread_id::uncache(uid, id);
+        }
+        self.get();
+    }
+
+    /// Check out a value from this thread's free list, or create a new one if
+    /// the free list is empty.
+    ///
+    /// Unlike `get`, every outstanding guard refers to a distinct value, so a
+    /// single thread may hold several values at once. When the guard is
+    /// dropped, its value is returned to the free list of the thread that
+    /// checked it out, where it can be reused by a subsequent `checkout`.
+    ///
+    /// Values handed out by `checkout` are never shared with `get`.
+    #[cfg(feature = "std")]
+    pub fn checkout(&self) -> PoolGuard<'_, T, F> {
+        let id = thread_id::current();
+        let value = self.free.lock().unwrap().pop(id);
+        // The lock is released before creating a new value so that a slow
+        // initialization function doesn't block other threads.
+        let value = match value {
+            Some(mut value) => {
+                if let Some(ref reset) = self.reset {
+                    reset(&mut value);
+                }
+                value
+            }
+            None => unwrap(self.new_value()),
+        };
+        PoolGuard { pool: self, thread_id: id, value: Some(value) }
+    }
+
+    /// Check out `n` distinct values, like calling `checkout` `n` times.
+    ///
+    /// The values are taken from this thread's free list while holding its
+    /// lock once, rather than once per value, and any values still missing
+    /// are created afterwards. Dropping the returned `Vec` returns every
+    /// value to the pool.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::with_fn(Vec::<u8>::new);
+    /// let mut bufs = pool.checkout_many(4);
+    /// for (i, buf) in bufs.iter_mut().enumerate() {
+    ///     buf.push(i as u8);
+    /// }
+    /// assert_eq!(4, bufs.len());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn checkout_many(&self, n: usize) -> Vec<PoolGuard<'_, T, F>> {
+        let id = thread_id::current();
+        let mut values = self.free.lock().unwrap().pop_many(id, n);
+        if let Some(ref reset) = self.reset {
+            for value in &mut values {
+                reset(value);
+            }
+        }
+        let missing = n - values.len();
+        values.extend((0..missing).map(|_| unwrap(self.new_value())));
+        values
+            .into_iter()
+            .map(|value| {
+                PoolGuard { pool: self, thread_id: id, value: Some(value) }
+            })
+            .collect()
+    }
+
+    /// Check out `N` distinct values, like `checkout_many(N)`, but as an
+    /// array.
+    ///
+    /// Since the number of values is known at compile time, this doesn't
+    /// allocate. Every value is returned to the pool when its guard is
+    /// dropped, e.g., when the whole array is dropped.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::with_fn(Vec::<u8>::new);
+    /// let [mut a, mut b, c, d] = pool.checkout_array();
+    /// a.push(1);
+    /// b.push(2);
+    /// assert!(c.is_empty() && d.is_empty());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn checkout_array<const N: usize>(&self) -> [PoolGuard<'_, T, F>; N] {
+        let id = thread_id::current();
+        let mut free = self.free.lock().unwrap();
+        let values: [Option<T>; N] = array::from_fn(|_| free.pop(id));
+        drop(free);
+        values.map(|value| {
+            let value = match value {
+                Some(mut value) => {
+                    if let Some(ref reset) = self.reset {
+                        reset(&mut value);
+                    }
+                    value
+                }
+                None => unwrap(self.new_value()),
+            };
+            PoolGuard { pool: self, thread_id: id, value: Some(value) }
+        })
+    }
+
+    /// Get a reference to this thread's value from the pool, creating it with
+    /// `create` if necessary.
+    ///
+    /// This is like `get`, except that if this thread has no value yet, then
+    /// it's created by `create` rather than by the pool's initialization
+    /// function, so the first value of each thread can depend on the context
+    /// it's first needed in. Values created by `fill` aren't used. If this
+    /// thread already has a value, then it's returned and `create` is
+    /// dropped without being called.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new_const(Vec::<u8>::new);
+    /// let buf = pool.get_or_create_with(|| Vec::with_capacity(4096));
+    /// assert!(buf.capacity() >= 4096);
+    /// assert!(pool.get().capacity() >= 4096);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn get_or_create_with<C>(&self, create: C) -> &T
+            where C: FnOnce() -> T {
+        let id = thread_id::current();
+        if let Some(val) = self.owner_get(id) {
+            return unsafe { &*val };
+        }
+        unsafe { &*unwrap(self.get_cached(id, || Ok(create()))) }
+    }
+
+    /// Get a reference to this thread's value from the pool, and run `init`
+    /// on it if this is the first time this thread got it.
+    ///
+    /// This is like `get`, except that `init` runs right after a new value
+    /// is created for this thread, including after `bump_generation`, or
+    /// right after this thread becomes the owner and gets the owner's value,
+    /// whether through `get` or `claim_ownership`, e.g., to register the
+    /// thread with some external system. Otherwise, `init` is dropped
+    /// without being called. So `init` runs once for each value, and a value
+    /// put in place by `swap_current_thread` counts as a new one. A thread
+    /// that took over the owner's value with `adopt` didn't get a new value,
+    /// so `init` doesn't run again if it already ran for the previous owner.
+    ///
+    /// ```rust
+    /// use std::cell::Cell;
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new(Box::new(|| 0));
+    /// let runs = Cell::new(0);
+    /// for _ in 0..3 {
+    ///     pool.get_with_init(|_| runs.set(runs.get() + 1));
+    /// }
+    /// assert_eq!(1, runs.get());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn get_with_init<I: FnOnce(&T)>(&self, init: I) -> &T {
+        let id = thread_id::current();
+        let val = match self.owner_get(id) {
+            Some(val) => val,
+            None => unwrap(self.get_cached(id, || self.new_value())),
+        };
+        // A value in the map never moves, and a new one is created for each
+        // generation, so its address tells it apart from any other value of
+        // this thread, including one that's stale.
+        let (key, mark) = if val == self.owner.val.get().cast() {
+            (0, 0)
+        } else {
+            (id, val as usize)
+        };
+        // The entry is written before `init` runs, in case `init` calls back
+        // into the pool.
+        let fresh =
+            self.inited.lock().recover().insert(key, mark) != Some(mark);
+        let val = unsafe { &*val };
+        if fresh {
+            init(val);
+        }
+        val
+    }
+
+    /// Get a reference to this thread's value from the pool, creating it with
+    /// `T::default` if necessary.
+    ///
+    /// This is `get_or_create_with(T::default)`, which is handy in tests
+    /// that want to skip an expensive initialization function. Note that
+    /// `new` and `with_fn` create the owner's value up front, with the
+    /// initialization function.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new_lazy(Box::new(|| vec![0u8; 1 << 20]));
+    /// assert!(pool.get_or_default().is_empty());
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn get_or_default(&self) -> &T where T: Default {
+        self.get_or_create_with(T::default)
+    }
+
+    /// Create a new value with the pool's initialization function, without
+    /// adding it to the pool.
+    ///
+    /// The value is owned by the caller. The pool itself isn't touched, and
+    /// values created by `fill` aren't used.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new_const(|| vec![0u8; 16]);
+    /// let buf = pool.create_one();
+    /// assert_eq!(16, buf.len());
+    /// assert!(pool.is_empty());
+    /// ```
+    pub fn create_one(&self) -> T {
+        (self.create)()
+    }
+
+    /// Takes one of the values created by `fill`, or creates a new value
+    /// with the initialization function if there are none left.
+    fn new_value(&self) -> Result<T, Infallible> {
+        self.spare_or(|| Ok((self.create)()))
+    }
+}
+
+#[cfg(feature = "std")]
+impl<U, F> Pool<Box<U>, F>
+        where U: ?Sized + Send, F: Fn() -> Box<U> + Send + Sync {
+    /// Get a reference to this thread's boxed value, without the box.
+    ///
+    /// This is `get` for pools of boxed trait objects, whose values would
+    /// otherwise need to be dereferenced twice:
+    ///
+    /// ```rust
+    /// use std::fmt::Display;
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let pool: Pool<Box<dyn Display + Send>, _> =
+    ///     Pool::with_fn(|| Box::new(5) as Box<dyn Display + Send>);
+    /// let value: &dyn Display = pool.get_dyn();
+    /// assert_eq!("5", value.to_string());
+    /// ```
+    #[inline(always)]
+    pub fn get_dyn(&self) -> &U {
+        self.get()
+    }
+}
+
+impl<T: Send, E> Pool<T, TryCreateFn<T, E>> {
+    /// Create a new memory pool with the given fallible initialization
+    /// function.
+    ///
+    /// The owner's value is created immediately, so if the initialization
+    /// function fails, then its error is returned and no pool is created.
+    pub fn try_new(
+        create: TryCreateFn<T, E>,
+    ) -> Result<Pool<T, TryCreateFn<T, E>>, E> {
+        let owner_val = (create)()?;
+        Ok(Pool::from_parts(create, Owner::new(Some(owner_val))))
+    }
+}
+
+impl<T, E, F> Pool<T, F>
+        where T: Send, F: Fn() -> Result<T, E> + Send + Sync {
+    /// Get a reference to a value from the pool, creating it with the
+    /// fallible initialization function if necessary.
+    ///
+    /// If creating the value fails, then the error is returned and nothing is
+    /// cached, so a subsequent call to `try_get` will try to create the value
+    /// again.
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    pub fn try_get(&self) -> Result<&T, E> {
+        let id = thread_id::current();
+        if let Some(val) = self.owner_get(id) {
+            return Ok(unsafe { &*val });
+        }
+        let create = || self.spare_or(|| (self.create)());
+        self.get_cached(id, create).map(|p| unsafe { &*p })
+    }
+}
+
+impl<T: Send, F> Pool<T, F> {
+    fn from_parts(create: F, owner: Owner<T>) -> Pool<T, F> {
+        Pool {
+            create,
+            reset: None,
+            finalize: None,
+            uid: AtomicUsize::new(0),
+            owner,
+            #[cfg(feature = "std")]
+            owner_alive: std::sync::Mutex::new(None),
+            #[cfg(feature = "std")]
+            inited: std::sync::Mutex::new(new_map()),
+            global: array::from_fn(|_| Mutex::new(Shard::new())),
+            free: Mutex::new(Free::new()),
+            max_idle: usize::MAX,
+            max_idle_per_thread: usize::MAX,
+            symmetric: false,
+            #[cfg(feature = "stats")]
+            stats: Stats::new(),
+            spare: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// Replace the initialization function of this pool.
+    ///
+    /// Values that have already been created aren't changed: `create` is only
+    /// used for values created from now on. To have every thread recreate
+    /// its value with `create`, call `bump_generation` too (or `clear`, to
+    /// drop the old values right away). Both also drop the values created by
+    /// `fill` that haven't been used yet.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let mut pool = Pool::new(Box::new(|| 1));
+    /// assert_eq!(&1, pool.get());
+    /// pool.replace_creator(Box::new(|| 2));
+    /// assert_eq!(&1, pool.get());
+    /// pool.bump_generation();
+    /// assert_eq!(&2, pool.get());
+    /// # }
+    /// ```
+    pub fn replace_creator(&mut self, create: F) {
+        self.create = create;
+    }
+
+    /// Drop every value cached by this pool.
+    ///
+    /// This releases the memory held for every thread that has used the pool,
+    /// including values returned by `checkout`. The owner is reset, and every
+    /// thread, including the next owner, gets a fresh value created with the
+    /// initialization function on its next call to `get`.
+    ///
+    /// Since this requires exclusive access to the pool, no locking is
+    /// needed and no references into the pool can be alive.
+    pub fn clear(&mut self) {
+        self.finalize_all();
+        for shard in &mut self.global {
+            shard.get_mut().recover().clear();
+        }
+        self.free.get_mut().recover().clear();
+        self.spare.get_mut().recover().clear();
+        #[cfg(feature = "std")]
+        self.inited.get_mut().recover().clear();
+        let generation = self.owner.generation.load(Relaxed);
+        self.owner = Owner::new(None);
+        self.owner.generation = AtomicUsize::new(generation);
+        // Invalidate every thread's cached value.
+        self.uid = AtomicUsize::new(0);
+    }
+
+    /// Make every thread get a fresh value on its next call to `get`.
+    ///
+    /// This is useful when the values depend on some shared state, e.g., a
+    /// configuration that has been reloaded. The bump itself is cheap: it
+    /// doesn't lock the map or touch any thread's value. Instead, each thread
+    /// notices on its next call to `get` that its value is from an older
+    /// generation, and creates a new one with the initialization function.
+    /// Idle values held for `checkout` and values created by `fill` are
+    /// dropped right away.
+    ///
+    /// References returned by `get` before the bump remain valid. Since the
+    /// pool can't know when they're dropped, the stale values are kept
+    /// until the pool is cleared or dropped.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new(Box::new(|| vec![0u8; 16]));
+    /// let old = pool.get();
+    /// pool.bump_generation();
+    /// assert!(!std::ptr::eq(old, pool.get()));
+    /// assert_eq!(2, pool.generation());
+    /// # }
+    /// ```
+    pub fn bump_generation(&self) {
+        self.owner.generation.fetch_add(1, Relaxed);
+        #[cfg(feature = "std")]
+        self.uid.store(thread_id::pool_uid(), Release);
+        self.reclaim_idle();
+    }
+
+    /// Drop every idle value in this pool and return how many were dropped.
+    ///
+    /// The idle values are those held for `checkout` after their guards were
+    /// dropped, and the spare values created by `fill`. This is a lighter
+    /// counterpart to `clear` that only needs `&self`, so e.g. a maintenance
+    /// thread can trim memory during quiet periods while other threads keep
+    /// using the pool.
+    ///
+    /// The value that each thread gets from `get` is never reclaimed, even
+    /// if that thread isn't using it right now. The references returned by
+    /// `get` aren't tracked, so the pool can't know whether one is still
+    /// alive. Use `clear` to drop those values too.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new(Box::new(|| vec![0u8; 16]));
+    /// drop(pool.checkout());
+    /// pool.fill(2);
+    /// assert_eq!(3, pool.reclaim_idle());
+    /// assert_eq!(0, pool.reclaim_idle());
+    /// # }
+    /// ```
+    pub fn reclaim_idle(&self) -> usize {
+        let spare = mem::take(&mut *self.spare.lock().unwrap());
+        let free = mem::replace(&mut *self.free.lock().unwrap(), Free::new());
+        let mut count = 0;
+        // The values are dropped after the locks are released, in case
+        // their destructors use the pool.
+        for value in spare.into_iter().chain(free.into_values()) {
+            self.discard(value);
+            count += 1;
+        }
+        count
+    }
+
+    /// Returns the current generation of this pool.
+    ///
+    /// The generation starts at `1` and is incremented by `bump_generation`.
+    pub fn generation(&self) -> usize {
+        self.owner.generation.load(Relaxed)
+    }
+
+    /// Get a reference to the owner's value from any thread.
+    ///
+    /// This works whether or not an owner has been assigned yet, so the
+    /// value created by `new` or `with_fn` can double as read-only state
+    /// shared by every thread, e.g., configuration that the initialization
+    /// function captured. It returns `None` if the owner's value hasn't been
+    /// created yet, which is only the case for pools created by `new_const`
+    /// (or reset by `clear`) until the owner first calls `get`.
+    ///
+    /// Since the owner may be using its value at the same time, this requires
+    /// `T: Sync`. After `bump_generation`, this still returns the value the
+    /// owner had before the bump.
+    pub fn peek_owner(&self) -> Option<&T> where T: Sync {
+        // The owner publishes its value by writing `gen` with `Release`.
+        if self.owner.gen.load(Acquire) != 0 {
+            Some(unsafe { &*self.owner.val.get().cast::<T>() })
+        } else {
+            None
+        }
+    }
+
+    /// Get a reference to the owner's value, without checking that the
+    /// current thread is the owner.
+    ///
+    /// This skips the atomic loads and the comparison on the owner's fast
+    /// path of `get`, so that a tight loop can check `is_owner` once, before
+    /// the loop, instead of on every iteration.
+    ///
+    /// # Safety
+    ///
+    /// The current thread must be the owner, and the owner's value must have
+    /// been created. Both hold if `is_owner` returns true after this thread's
+    /// call to `get`, `claim_ownership` or `adopt` has returned, until the
+    /// pool is cleared. After `bump_generation`, this still returns the value
+    /// the owner had before the bump, unlike `get`.
+    ///
+    /// The owner's value must also stay in place for as long as the returned
+    /// reference is alive. So while it is, the current thread must not call
+    /// `remove_current_thread` or `swap_current_thread`, which move the
+    /// owner's value out, and the pool must not be cleared, taken apart by
+    /// `into_inner` or `drain_into`, or dropped, which passes the value to
+    /// the finalizer. (Those take the pool by value or by mutable reference,
+    /// so the borrow checker rejects them while the reference is alive, but
+    /// a raw pointer derived from the reference must not be used after them
+    /// either.)
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new(Box::new(|| 5));
+    /// pool.get();
+    /// assert!(pool.is_owner());
+    /// let mut sum = 0;
+    /// for _ in 0..10 {
+    ///     sum += unsafe { *pool.get_owner_unchecked() };
+    /// }
+    /// assert_eq!(50, sum);
+    /// # }
+    /// ```
+    #[inline(always)]
+    pub unsafe fn get_owner_unchecked(&self) -> &T {
+        self.record(Hit);
+        &*self.owner.val.get().cast::<T>()
+    }
+
+    /// Create a weak handle to this pool.
+    ///
+    /// A thread holding a `WeakPool` doesn't keep the pool alive. Instead,
+    /// it calls `upgrade` before using the pool, which fails once every
+    /// `Arc` to the pool has been dropped, so the thread can notice that the
+    /// pool has been torn down and stop.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use std::sync::Arc;
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let pool = Arc::new(Pool::with_fn(|| 5));
+    /// let weak = pool.downgrade();
+    /// assert_eq!(5, *weak.upgrade().unwrap().get());
+    /// drop(pool);
+    /// assert!(weak.upgrade().is_none());
+    /// # }
+    /// ```
+    pub fn downgrade(self: &Arc<Self>) -> WeakPool<T, F> {
+        WeakPool { pool: Arc::downgrade(self) }
+    }
+
+    /// Drop the current thread's value when the current thread exits.
+    ///
+    /// By default, the values of a thread that has exited are kept until the
+    /// pool is cleared or dropped, so a program that keeps spawning short
+    /// lived threads that use the pool keeps growing it. Calling this once on
+    /// a thread drops its value as soon as it exits. The callback only holds a
+    /// weak handle, so it doesn't keep the pool alive, and does nothing if
+    /// the pool has been dropped by then.
+    ///
+    /// # Safety
+    ///
+    /// The value is dropped while the thread's thread local destructors run,
+    /// so no reference to it may be used after that. This includes a
+    /// reference stored in a thread local whose destructor runs later, and,
+    /// if `T: Sync`, a reference sent to another thread. The caller must
+    /// guarantee this doesn't happen, as with `remove_current_thread`.
+    #[cfg(feature = "std")]
+    pub unsafe fn cleanup_on_thread_exit(self: &Arc<Self>)
+            where T: 'static, F: Send + Sync + 'static {
+        let weak = Arc::downgrade(self);
+        thread_id::on_exit(Box::new(move |id| {
+            if let Some(pool) = weak.upgrade() {
+                if let Some(value) = pool.remove_thread(id) {
+                    pool.discard(value);
+                }
+            }
+        }));
+    }
+
+    /// Release excess memory held by the bookkeeping of this pool.
+    ///
+    /// After a burst of activity, e.g., many threads using the pool at once,
+    /// or many values being checked out at once, the maps and lists used to
+    /// track values keep their capacity. This returns as much of it as
+    /// possible to the allocator. No values are dropped.
+    pub fn shrink_to_fit(&mut self) {
+        for shard in &mut self.global {
+            shard.get_mut().recover().shrink_to_fit();
+        }
+        self.free.get_mut().recover().shrink_to_fit();
+        self.spare.get_mut().recover().shrink_to_fit();
+    }
+
+    /// Returns the number of threads that have a value cached by `get`.
+    ///
+    /// This counts the owner, if one has been assigned, plus every other
+    /// thread with a value in the pool. Values held for `checkout` aren't
+    /// counted.
+    ///
+    /// Since other threads may be using the pool concurrently, the count may
+    /// be stale by the time it's returned.
+    pub fn len(&self) -> usize {
+        let owner = self.owner.id.load(Relaxed);
+        let mut len = (owner != 0) as usize;
+        for shard in &self.global {
+            let shard = shard.lock().unwrap();
+            len += shard.len();
+            // An owner whose value is stale keeps its new value in a shard.
+            if owner != 0 && shard.contains(owner) {
+                len -= 1;
+            }
+        }
+        len
+    }
+
+    /// Returns the number of threads the maps holding the values of
+    /// non-owner threads can hold without reallocating.
+    ///
+    /// This is meant for checking that `with_thread_capacity` reserved
+    /// enough room, or whether `shrink_to_fit` would release anything. Since
+    /// threads are spread over several maps, one of them may need to grow
+    /// before the total capacity is reached.
+    #[cfg(feature = "std")]
+    pub fn capacity(&self) -> usize {
+        self.global.iter().map(|shard| shard.lock().unwrap().capacity()).sum()
+    }
+
+    /// Returns the id of the owner, or `None` if no owner has been assigned.
+    ///
+    /// This is the id that `is_owner` compares against, and that appears in
+    /// `thread_ids`. It's meant for debugging which thread became the owner.
+    pub fn owner_thread_id(&self) -> Option<usize> {
+        match self.owner.id.load(Relaxed) {
+            0 => None,
+            id => Some(id),
+        }
+    }
+
+    /// Returns the ids of the threads that have a value cached by `get`, in
+    /// ascending order.
+    ///
+    /// These are the same threads counted by `len`, and are the ids returned
+    /// by `ThreadIdSource` for `CurrentThread`. This is meant for debugging,
+    /// e.g., to find the threads that are holding on to values. Like `len`,
+    /// the ids may be stale by the time they're returned.
+    pub fn thread_ids(&self) -> Vec<usize> {
+        let mut ids = Vec::new();
+        let owner = self.owner.id.load(Relaxed);
+        if owner != 0 {
+            ids.push(owner);
+        }
+        for shard in &self.global {
+            ids.extend(shard.lock().unwrap().thread_ids());
+        }
+        ids.sort_unstable();
+        // An owner whose value is stale keeps its new value in a shard.
+        ids.dedup();
+        ids
+    }
+
+    /// Returns the total memory held by every value in the pool, in bytes,
+    /// as reported by `PoolSized`.
+    ///
+    /// This includes the owner's value, the values of every other thread
+    /// (including values made stale by `bump_generation`), idle values held
+    /// for `checkout` and values created by `fill`. Values that are checked
+    /// out aren't counted.
+    ///
+    /// Since the values of other threads are read, this requires `T: Sync`.
+    /// Each lock of the pool is held while the values it guards are read. If
+    /// `pool_size` panics, then the lock is released without poisoning it,
+    /// so the pool stays usable.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new(Box::new(|| Vec::<u8>::with_capacity(64)));
+    /// pool.get();
+    /// assert!(pool.total_allocated() >= 64);
+    /// # }
+    /// ```
+    pub fn total_allocated(&self) -> usize where T: PoolSized + Sync {
+        let mut total = self.peek_owner().map_or(0, T::pool_size);
+        for shard in &self.global {
+            total += unpoisoned(shard, |shard| {
+                shard.values().map(T::pool_size).sum::<usize>()
+            });
+        }
+        total += unpoisoned(&self.free, |free| {
+            free.values().map(T::pool_size).sum::<usize>()
+        });
+        total + unpoisoned(&self.spare, |spare| {
+            spare.iter().map(T::pool_size).sum::<usize>()
+        })
+    }
+
+    /// Returns true if no thread has a value cached by `get`.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Consume the pool and return every value it holds.
+    ///
+    /// The first element is the owner's value, which is `None` only if it was
+    /// never created (see `new_const`). The second element contains the
+    /// values of every other thread, including values held for `checkout`,
+    /// in an unspecified order.
+    pub fn into_inner(mut self) -> (Option<T>, Vec<T>) {
+        self.take_all()
+    }
+
+    /// Consume the pool and pass every value it holds to `sink`.
+    ///
+    /// This is like `into_inner`, except that the values are passed to
+    /// `sink` one at a time instead of being collected into a `Vec`. The
+    /// owner's value comes first, if it was created, followed by the values
+    /// of every other thread, including values held for `checkout`, in an
+    /// unspecified order. This is useful for, e.g., aggregating per-thread
+    /// counters at shutdown. If `sink` panics, then the remaining values are
+    /// dropped with the pool.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use std::cell::Cell;
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::new(Box::new(|| Cell::new(0)));
+    /// std::thread::scope(|s| {
+    ///     for _ in 0..4 {
+    ///         s.spawn(|| pool.get().set(pool.get().get() + 1));
+    ///     }
+    /// });
+    /// let mut total = 0;
+    /// pool.drain_into(|count| total += count.get());
+    /// assert_eq!(4, total);
+    /// # }
+    /// ```
+    pub fn drain_into<G: FnMut(T)>(mut self, mut sink: G) {
+        if let Some(owner) = self.owner.take() {
+            sink(owner);
+        }
+        for shard in &mut self.global {
+            let shard = mem::replace(shard.get_mut().recover(), Shard::new());
+            shard.into_values().for_each(&mut sink);
+        }
+        let free = mem::replace(self.free.get_mut().recover(), Free::new());
+        free.into_values().for_each(&mut sink);
+        self.spare.get_mut().recover().drain(..).for_each(sink);
+    }
+
+    /// Returns an iterator over every value cached by `get`.
+    ///
+    /// This yields the owner's value, if an owner has been assigned, followed
+    /// by the value of every other thread that has used the pool. The order
+    /// of the values is unspecified. Values held for `checkout` aren't
+    /// visited, but values made stale by `bump_generation` are.
+    ///
+    /// Since this requires exclusive access to the pool, no locking is
+    /// needed and no references into the pool can be alive.
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        let owned = self.owner.id.load(Relaxed) != 0
+            && self.owner.gen.load(Relaxed) != 0;
+        let owner = if owned {
+            Some(unsafe { self.owner.val.get_mut().assume_init_mut() })
+        } else {
+            None
+        };
+        let global = self
+            .global
+            .iter_mut()
+            .flat_map(|shard| shard.get_mut().recover().values_mut());
+        owner.into_iter().chain(global)
+    }
+
+    /// Call `f` on every value cached by `get`, until it returns `Break`.
+    ///
+    /// The values are visited in the same order as `iter_mut`: the owner's
+    /// value first, then the values of every other thread in an unspecified
+    /// order. Returns `Break` if `f` stopped the iteration early.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use std::ops::ControlFlow;
+    ///
+    /// use mempool::Pool;
+    ///
+    /// let mut pool = Pool::with_fn(|| 0);
+    /// pool.get();
+    /// let flow = pool.for_each_mut(|v| {
+    ///     *v += 1;
+    ///     ControlFlow::Break(())
+    /// });
+    /// assert!(flow.is_break());
+    /// assert_eq!(1, *pool.get());
+    /// # }
+    /// ```
+    pub fn for_each_mut<G>(&mut self, f: G) -> ControlFlow<()>
+            where G: FnMut(&mut T) -> ControlFlow<()> {
+        self.iter_mut().try_for_each(f)
+    }
+
+    /// Group every value cached by `get` by the key that `key` returns for
+    /// it.
+    ///
+    /// This visits the same values as `iter_mut`, and each value ends up in
+    /// exactly one group, so the mutable references stay unique across all
+    /// of the groups. The order of the values within a group is unspecified.
+    /// This is useful for, e.g., merging per-thread accumulators into one
+    /// aggregate per key at shutdown.
+    ///
+    /// ```rust
+    /// use mempool::Pool;
+    ///
+    /// let mut pool = Pool::with_fn(|| vec![1, 2]);
+    /// std::thread::scope(|s| {
+    ///     s.spawn(|| pool.get().len());
+    /// });
+    /// pool.get();
+    /// for (len, values) in pool.group_by_mut(|v| v.len()) {
+    ///     assert_eq!(2, len);
+    ///     values.into_iter().for_each(|v| v.push(3));
+    /// }
+    /// assert_eq!(&[1, 2, 3], &pool.get()[..]);
+    /// ```
+    #[cfg(feature = "std")]
+    pub fn group_by_mut<K, G>(&mut self, mut key: G) -> HashMap<K, Vec<&mut T>>
+            where K: Eq + Hash, G: FnMut(&T) -> K {
+        let mut groups: HashMap<K, Vec<&mut T>> = HashMap::new();
+        for value in self.iter_mut() {
+            groups.entry(key(value)).or_default().push(value);
+        }
+        groups
+    }
+
+    /// Run the reset function given to `with_reset` on every value cached by
+    /// `get`.
+    ///
+    /// This visits the same values as `iter_mut`, e.g., to return every
+    /// thread's value to a clean state between the phases of a program. If
+    /// the pool has no reset function, then this does nothing.
+    pub fn reset_all(&mut self) {
+        if let Some(reset) = self.reset.clone() {
+            for value in self.iter_mut() {
+                reset(value);
+            }
+        }
+    }
+
+    /// Returns statistics about how often `get` found an existing value.
+    ///
+    /// The counters are updated with relaxed atomics, so they may lag behind
+    /// other threads.
+    #[cfg(feature = "stats")]
+    pub fn stats(&self) -> PoolStats {
+        PoolStats {
+            hits: self.stats.hits.load(Relaxed),
+            misses: self.stats.misses.load(Relaxed),
+            threads: self.len(),
+        }
+    }
+
+    /// Like `self.owner.get`, but records a hit.
+    #[inline(always)]
+    fn owner_get(&self, id: usize) -> Option<*mut T> {
+        let val = self.owner.get(id);
+        if val.is_some() {
+            self.record(Hit);
+        }
+        val
+    }
+
+    /// Records a miss, after a value has been created for the given thread.
+    fn created(&self, thread_id: usize) {
+        self.record(Miss);
+        #[cfg(feature = "tracing")]
+        tracing::trace!(thread_id, "mempool: created a value");
+        #[cfg(not(feature = "tracing"))]
+        let _ = thread_id;
+    }
+
+    #[inline(always)]
+    fn record(&self, event: Event) {
+        #[cfg(feature = "stats")]
+        {
+            let counter = match event {
+                Hit => &self.stats.hits,
+                Miss => &self.stats.misses,
+            };
+            counter.fetch_add(1, Relaxed);
+        }
+        #[cfg(not(feature = "stats"))]
+        let _ = event;
+    }
+
+    /// Removes the value of the given thread from the current generation,
+    /// if it has one.
+    ///
+    /// The caller must be the given thread (or be running on its behalf
+    /// while it exits), and must uphold the contract of
+    /// `remove_current_thread`.
+    #[cfg(feature = "std")]
+    unsafe fn remove_thread(&self, id: usize) -> Option<T> {
+        // The current thread's cache may point at the value being removed.
+        // This is also true when it's called back as the thread exits, and
+        // a thread local destructor may still call `get` after that.
+        let uid = self.uid.load(Acquire);
+        if uid != 0 {
+            thread_id::uncache(uid, id);
+        }
+        self.forget_init(id);
+        let gen = self.owner.generation.load(Relaxed);
+        if self.owner.id.load(Acquire) == id
+            && self.owner.gen.load(Relaxed) == gen
+        {
+            self.owner.gen.store(0, Relaxed);
+            return Some(self.owner.val.get().cast::<T>().read());
+        }
+        let mut shard = self.shard(id).lock().unwrap();
+        shard.remove(id, gen)
+    }
+
+    /// Moves every value out of the pool.
+    fn take_all(&mut self) -> (Option<T>, Vec<T>) {
+        let owner = self.owner.take();
+        let mut values = Vec::new();
+        for shard in &mut self.global {
+            let shard = mem::replace(shard.get_mut().recover(), Shard::new());
+            values.extend(shard.into_values());
+        }
+        let free = mem::replace(self.free.get_mut().recover(), Free::new());
+        values.extend(free.into_values());
+        values.append(self.spare.get_mut().recover());
+        (owner, values)
+    }
+
+    /// Passes every value in the pool to the finalizer, if there is one.
+    fn finalize_all(&mut self) {
+        if let Some(finalize) = self.finalize.clone() {
+            let (owner, values) = self.take_all();
+            // The owner's value is finalized last, after every other value.
+            values.into_iter().chain(owner).for_each(|value| finalize(value));
+        }
+    }
+
+    /// Panics if the current thread holds a `TrackedRef` into this pool.
+    #[cfg(feature = "debug-tracking")]
+    fn assert_not_borrowed(&self, method: &str) {
+        assert!(
+            !thread_id::borrowed(self as *const Self as usize),
+            "Pool::{} called while a TrackedRef into the pool is alive on \
+             this thread",
+            method,
+        );
+    }
+
+    /// Passes a value that the pool no longer needs to the finalizer, or
+    /// drops it if there is none.
+    fn discard(&self, value: T) {
+        match self.finalize {
+            Some(ref finalize) => finalize(value),
+            None => drop(value),
+        }
+    }
+
+    fn shard(
+        &self,
+        thread_id: usize,
+    ) -> &Mutex<Shard<T>> {
+        &self.global[thread_id % SHARDS]
+    }
+
+    fn put(&self, thread_id: usize, value: T) {
+        let mut free = self.free.lock().unwrap();
+        let excess = free.push(
+            thread_id,
+            value,
+            self.max_idle,
+            self.max_idle_per_thread,
+        );
+        drop(free);
+        // An excess value is dropped after the lock is released, so that a
+        // panic in its destructor can't poison the lock.
+        excess.into_iter().for_each(|value| self.discard(value));
+    }
+
+    /// Records that the thread with the given id has just become the owner,
+    /// along with its flag that says whether it's alive, so that `adopt` can
+    /// tell when it has exited.
+    #[cfg(feature = "std")]
+    fn owned_by(&self, id: usize) {
+        let alive = thread_id::alive(id).map(|alive| (id, alive));
+        *self.owner_alive.lock().recover() = alive;
+    }
+
+    /// Forgets that `get_with_init` has initialized the value of the thread
+    /// with the given id, before the value is moved out, so that it doesn't
+    /// count as initialized if the thread gets a value at the same place.
+    #[cfg(feature = "std")]
+    fn forget_init(&self, id: usize) {
+        let mut inited = self.inited.lock().recover();
+        inited.remove(&id);
+        if self.owner.id.load(Acquire) == id {
+            inited.remove(&0);
+        }
+    }
+
+    /// Like `get_slow`, but first checks the current thread's cache of the
+    /// last value it got from the slow path, which avoids both the lock and
+    /// the map lookup.
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    fn get_cached<E, C>(
+        &self,
+        thread_id: usize,
+        create: C,
+    ) -> Result<*mut T, E> where C: FnOnce() -> Result<T, E> {
+        let uid = self.uid();
+        if let Some(val) = thread_id::cached(uid, thread_id) {
+            self.record(Hit);
+            return Ok(val.cast());
+        }
+        let val = self.get_slow(thread_id, create)?;
+        // The owner's value lives inline in the pool, so it moves whenever
+        // the pool does. Only values in the shards, which never move, can be
+        // cached.
+        if val != self.owner.val.get().cast() {
+            thread_id::cache(uid, thread_id, val.cast());
+        }
+        Ok(val)
+    }
+
+    /// Returns a unique id for this pool, assigning one if necessary.
+    ///
+    /// The id is never reused by another pool, and is reset by `clear`, so
+    /// that a thread's cached value can't outlive the value itself. It's
+    /// also replaced by `bump_generation`, after the generation is bumped,
+    /// so a thread that sees the new id also sees the new generation.
+    #[cfg(feature = "std")]
+    #[inline(always)]
+    fn uid(&self) -> usize {
+        let uid = self.uid.load(Acquire);
+        if uid != 0 {
+            return uid;
+        }
+        let fresh = thread_id::pool_uid();
+        match self.uid.compare_exchange(0, fresh, Relaxed, Acquire) {
+            Ok(_) => fresh,
+            Err(uid) => uid,
+        }
+    }
+
+    /// Returns a pointer to this thread's value, creating it with `create`
+    /// if necessary.
+    ///
+    /// The pointer is only ever dereferenced by the thread it was returned
+    /// to, and remains valid for the lifetime of the pool since values never
+    /// move once they've been created.
+    #[cold]
+    fn get_slow<E, C>(
+        &self,
+        thread_id: usize,
+        create: C,
+    ) -> Result<*mut T, E> where C: FnOnce() -> Result<T, E> {
+        // The current thread's id must not be given to another thread once
+        // it has been handed a value for it. See the `thread_id` module.
+        #[cfg(feature = "std")]
+        thread_id::used(thread_id);
+        let owner = self.owner.id.load(Acquire);
+        // A thread that already has a value in the map while the owner slot
+        // is free, which `swap_current_thread` can leave behind, keeps it.
+        if owner == 0 {
+            let gen = self.owner.generation.load(Relaxed);
+            if let Some(val) = self.shard(thread_id).lock().unwrap().get(
+                thread_id,
+                gen,
+            ) {
+                self.record(Hit);
+                return Ok(val);
+            }
+        }
+        // The owner slot is claimed with `AcqRel` and read with `Acquire`.
+        // Nothing is published through the slot today: the owner's value is
+        // either written before the pool is shared, or created lazily by the
+        // owner itself, and is only ever accessed by the owner. But this
+        // makes the slot a proper synchronization point, so that anything
+        // written before the slot is claimed is visible to any thread that
+        // observes the new owner.
+        //
+        // If another thread wins the race to become the owner, then this
+        // thread uses the map like any other non-owner.
+        let claimed = owner == 0
+            && !self.symmetric
+            && self
+                .owner
+                .id
+                .compare_exchange(0, thread_id, AcqRel, Acquire)
+                .is_ok();
+        #[cfg(feature = "std")]
+        if claimed {
+            self.owned_by(thread_id);
+        }
+        let gen = self.owner.generation.load(Relaxed);
+        if claimed || owner == thread_id {
+            // The owner's value can't be replaced once it may have been
+            // handed out. So if it's stale, then the owner gets its new
+            // value from the map like any other thread.
+            let owner_gen = self.owner.gen.load(Relaxed);
+            if owner_gen == 0 || owner_gen == gen {
+                return unsafe { self.owner_value(gen, create) };
+            }
+        }
+        let shard = self.shard(thread_id);
+        if let Some(val) = shard.lock().unwrap().get(thread_id, gen) {
+            self.record(Hit);
+            return Ok(val);
+        }
+        // The value is created without holding the lock. Otherwise, if the
+        // initialization function panicked, then the lock would be poisoned,
+        // and every subsequent call would panic too. If creation fails, then
+        // nothing is inserted, so the next call will try again.
+        let value = create()?;
+        self.created(thread_id);
+        let (val, unused) =
+            shard.lock().unwrap().insert(thread_id, gen, value);
+        // The initialization function may have called back into the pool
+        // and created this thread's value already. The unused value is
+        // dropped after the lock is released, for the same reason as above.
+        unused.into_iter().for_each(|value| self.discard(value));
+        Ok(val)
+    }
+
+    /// Takes one of the values created by `fill`, or creates a new value
+    /// with `create` if there are none left.
+    fn spare_or<E, C>(&self, create: C) -> Result<T, E>
+            where C: FnOnce() -> Result<T, E> {
+        let spare = self.spare.lock().unwrap().pop();
+        match spare {
+            Some(value) => Ok(value),
+            None => create(),
+        }
+    }
+
+    /// Returns a pointer to the owner's value, creating it in generation
+    /// `gen` with `create` if it hasn't been created yet.
+    ///
+    /// The caller must be the owner.
+    unsafe fn owner_value<E, C>(
+        &self,
+        gen: usize,
+        create: C,
+    ) -> Result<*mut T, E> where C: FnOnce() -> Result<T, E> {
+        let owner_gen = &self.owner.gen;
+        let val = self.owner.val.get().cast::<T>();
+        if owner_gen.load(Relaxed) != 0 {
+            self.record(Hit);
+        } else {
+            let v = create()?;
+            self.created(self.owner.id.load(Relaxed));
+            // `create` may have called back into the pool and created the
+            // owner's value already. In that case, it may have been handed
+            // out, so it must not be overwritten.
+            if owner_gen.load(Relaxed) == 0 {
+                val.write(v);
+                // Publishes the value to `peek_owner`.
+                owner_gen.store(gen, Release);
+            } else {
+                self.discard(v);
+            }
+        }
+        Ok(val)
+    }
+}
+
+/// Recovers the data guarded by a lock, even if it has been poisoned.
+trait Recover<G> {
+    fn recover(self) -> G;
+}
+
+#[cfg(feature = "std")]
+impl<G> Recover<G> for Result<G, std::sync::PoisonError<G>> {
+    fn recover(self) -> G {
+        self.unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+}
+
+impl<G> Recover<G> for Result<G, Infallible> {
+    fn recover(self) -> G {
+        unwrap(self)
+    }
+}
+
+/// Runs `f` on the data guarded by `lock`, while holding it.
+///
+/// `f` must not modify the data. If it panics, then the lock is released
+/// before the panic resumes, so it isn't poisoned: the data is untouched, and
+/// a poisoned lock would make every later call that takes it panic.
+fn unpoisoned<G, R, U: FnOnce(&G) -> R>(lock: &Mutex<G>, f: U) -> R {
+    let guard = lock.lock().recover();
+    #[cfg(feature = "std")]
+    {
+        use std::panic::{self, AssertUnwindSafe};
+
+        let result = panic::catch_unwind(AssertUnwindSafe(|| f(&*guard)));
+        drop(guard);
+        result.unwrap_or_else(|payload| panic::resume_unwind(payload))
+    }
+    #[cfg(not(feature = "std"))]
+    f(&*guard)
+}
+
+/// Unwraps the result of an infallible initialization function.
+fn unwrap<T>(result: Result<T, Infallible>) -> T {
+    match result {
+        Ok(t) => t,
+        Err(never) => match never {},
+    }
+}
+
+/// A value that can report how much memory it holds, for
+/// `Pool::total_allocated`.
+pub trait PoolSized {
+    /// Returns the number of bytes of memory held by this value, not
+    /// counting the size of the value itself.
+    fn pool_size(&self) -> usize;
+}
+
+impl<T> PoolSized for Vec<T> {
+    fn pool_size(&self) -> usize {
+        self.capacity() * mem::size_of::<T>()
+    }
+}
+
+impl PoolSized for String {
+    fn pool_size(&self) -> usize {
+        self.capacity()
+    }
+}
+
+/// A source of ids identifying the caller of `Pool::get_from`.
+///
+/// # Safety
+///
+/// Implementations must uphold the safety contract of `Pool::get_with_id`:
+/// the same id must never be returned to two threads that use a pool at the
+/// same time, and ids must not be mixed with the ids used by `Pool::get`,
+/// except for `CurrentThread`'s.
+pub unsafe trait ThreadIdSource {
+    /// Returns the id of the caller.
+    fn current_id(&self) -> usize;
+}
+
+/// The ids used by `Pool::get`, i.e., the id of the current OS thread.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CurrentThread;
+
+#[cfg(feature = "std")]
+unsafe impl ThreadIdSource for CurrentThread {
+    #[inline(always)]
+    fn current_id(&self) -> usize {
+        thread_id::current()
+    }
+}
+
+/// A weak handle to a pool, created by `Pool::downgrade`.
+///
+/// The handle doesn't keep the pool alive, and must be upgraded to an `Arc`
+/// before the pool can be used.
+pub struct WeakPool<T: Send, F = CreateFn<T>> {
+    pool: Weak<Pool<T, F>>,
+}
+
+impl<T: Send, F> WeakPool<T, F> {
+    /// Returns the pool, unless it has been dropped.
+    pub fn upgrade(&self) -> Option<Arc<Pool<T, F>>> {
+        self.pool.upgrade()
+    }
+}
+
+impl<T: Send, F> Clone for WeakPool<T, F> {
+    fn clone(&self) -> WeakPool<T, F> {
+        WeakPool { pool: self.pool.clone() }
+    }
+}
+
+impl<T: Send, F> fmt::Debug for WeakPool<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "WeakPool")
+    }
+}
+
+/// A clonable handle to a thread's value, returned by `Pool::get_rc`.
+///
+/// The handle dereferences to the value, and keeps the pool alive until it
+/// and all of its clones are dropped. It can't be sent to another thread.
+#[cfg(feature = "std")]
+pub struct PoolRc<T: Send, F = CreateFn<T>> {
+    inner: Rc<RcInner<T, F>>,
+}
+
+#[cfg(feature = "std")]
+struct RcInner<T: Send, F> {
+    pool: Arc<Pool<T, F>>,
+    // A value is never dropped while the pool is alive and shared, so this
+    // stays valid for as long as `pool` is held.
+    value: *const T,
+}
+
+#[cfg(feature = "std")]
+impl<T: Send, F> Deref for PoolRc<T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.inner.value }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Send, F> PoolRc<T, F> {
+    /// Returns the pool that this handle's value belongs to.
+    pub fn pool(&self) -> &Arc<Pool<T, F>> {
+        &self.inner.pool
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: Send, F> Clone for PoolRc<T, F> {
+    fn clone(&self) -> PoolRc<T, F> {
+        PoolRc { inner: self.inner.clone() }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: fmt::Debug + Send, F> fmt::Debug for PoolRc<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PoolRc({:?})", **self)
+    }
+}
+
+/// A reference to a thread's value, returned by `Pool::get_tracked`.
+///
+/// This is a newtype that dereferences to the value. With the
+/// `debug-tracking` feature, it's counted as an outstanding reference on the
+/// thread that got it until it's dropped, so it can't be sent to another
+/// thread (but the reference it dereferences to can be, if `T: Sync`).
+pub struct TrackedRef<'a, T: 'a> {
+    value: &'a T,
+    #[cfg(feature = "debug-tracking")]
+    pool: usize,
+    _not_send: PhantomData<*const ()>,
+}
+
+impl<'a, T> Deref for TrackedRef<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value
+    }
+}
+
+#[cfg(feature = "debug-tracking")]
+impl<'a, T> Drop for TrackedRef<'a, T> {
+    fn drop(&mut self) {
+        thread_id::release(self.pool);
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for TrackedRef<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TrackedRef({:?})", self.value)
+    }
+}
+
+/// A value checked out of a pool with `Pool::checkout`.
+///
+/// The guard dereferences to the value, which it has exclusive access to.
+/// When the guard is dropped, the value is returned to the pool.
+pub struct PoolGuard<'a, T: Send + 'a, F: 'a = CreateFn<T>> {
+    pool: &'a Pool<T, F>,
+    thread_id: usize,
+    value: Option<T>,
+}
+
+impl<'a, T: Send, F> Deref for PoolGuard<'a, T, F> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.value.as_ref().unwrap()
+    }
+}
+
+impl<'a, T: Send, F> DerefMut for PoolGuard<'a, T, F> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.value.as_mut().unwrap()
+    }
+}
+
+impl<'a, T: Send, F> PoolGuard<'a, T, F> {
+    /// Take the value out of the guard, so that it isn't returned to the
+    /// pool.
+    ///
+    /// This hands ownership of the value to the caller, e.g., to send a
+    /// filled buffer to another thread. The pool creates a new value (or
+    /// reuses another idle one) the next time one is checked out.
+    ///
+    /// ```rust
+    /// # #[cfg(feature = "std")] {
+    /// use mempool::Pool;
+    ///
+    /// let pool = Pool::with_fn(Vec::<u8>::new);
+    /// let mut buf = pool.checkout();
+    /// buf.extend_from_slice(b"hello");
+    /// let buf: Vec<u8> = buf.detach();
+    /// assert_eq!(b"hello", &buf[..]);
+    /// assert!(pool.checkout().is_empty());
+    /// # }
+    /// ```
+    pub fn detach(mut self) -> T {
+        self.value.take().unwrap()
+    }
+}
+
+impl<'a, T: Send, F> Drop for PoolGuard<'a, T, F> {
+    fn drop(&mut self) {
+        if let Some(value) = self.value.take() {
+            self.pool.put(self.thread_id, value);
+        }
+    }
+}
+
+impl<'a, T: fmt::Debug + Send, F> fmt::Debug for PoolGuard<'a, T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PoolGuard({:?})", **self)
+    }
+}
+
+#[cfg(test)]
+#[cfg(feature = "nightly")]
+mod bench;
+
+#[cfg(test)]
+#[cfg(feature = "loom")]
+mod model;
+
+#[cfg(test)]
+#[cfg(all(feature = "std", not(feature = "loom")))]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::HashSet;
+    use std::ops::ControlFlow;
+    use std::ptr;
+    use std::rc::Rc;
+    use std::sync::Arc;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering::SeqCst;
+    use std::thread;
+
+    use super::{CreateFn, Pool, SHARDS, TryCreateFn};
+    use super::local::LocalPool;
+    use super::scoped::ScopedPool;
+    use super::thread_id::{self, COUNTER};
 
     #[derive(Debug, Eq, PartialEq)]
     struct Dummy(usize);
 
-    fn dummy() -> CreateFn<Dummy> {
-        let count = AtomicUsize::new(0);
-        Box::new(move || {
-            Dummy(count.fetch_add(1, SeqCst))
-        })
+    fn dummy() -> CreateFn<Dummy> {
+        let count = AtomicUsize::new(0);
+        Box::new(move || {
+            Dummy(count.fetch_add(1, SeqCst))
+        })
+    }
+
+    #[test]
+    fn empty() {
+        let pool = Pool::new(dummy());
+        assert_eq!(&Dummy(0), pool.get());
+    }
+
+    #[test]
+    fn reuse() {
+        // This tests that multiple accesses from the same thread don't create
+        // new values.
+        let pool = Pool::new(dummy());
+        {
+            assert_eq!(&Dummy(0), pool.get());
+        }
+        assert_eq!(&Dummy(0), pool.get());
+        assert_eq!(&Dummy(0), pool.get());
+    }
+
+    #[test]
+    fn no_reuse() {
+        // This tests that a pool's values aren't shared between threads.
+        // i.e., the init function is called when another thread tries to
+        // get a value.
+        let pool = Arc::new(Pool::new(dummy()));
+        let val = pool.get();
+        assert_eq!(&Dummy(0), val);
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(1), pool2.get());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn scoped_reuse() {
+        let base = 10;
+        let count = AtomicUsize::new(0);
+        let pool: ScopedPool<Dummy> = Pool::new(Box::new(|| {
+            Dummy(base + count.fetch_add(1, SeqCst))
+        }));
+        assert_eq!(&Dummy(10), pool.get());
+        assert_eq!(&Dummy(10), pool.get());
+    }
+
+    #[test]
+    fn scoped_no_reuse() {
+        let count = AtomicUsize::new(0);
+        let pool: ScopedPool<Dummy> =
+            Pool::new(Box::new(|| Dummy(count.fetch_add(1, SeqCst))));
+        assert_eq!(&Dummy(0), pool.get());
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(&Dummy(1), pool.get()));
+        });
+        assert_eq!(2, count.load(SeqCst));
+    }
+
+    #[test]
+    fn scoped_drops_values_with_pool() {
+        struct Counted<'a>(&'a AtomicUsize);
+        impl<'a> Drop for Counted<'a> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, SeqCst);
+            }
+        }
+
+        let dropped = AtomicUsize::new(0);
+        {
+            let pool: ScopedPool<Counted> =
+                Pool::new(Box::new(|| Counted(&dropped)));
+            pool.get();
+            thread::scope(|s| {
+                for _ in 0..3 {
+                    s.spawn(|| { pool.get(); });
+                }
+            });
+            assert_eq!(0, dropped.load(SeqCst));
+        }
+        assert!(dropped.load(SeqCst) >= 2);
+    }
+
+    #[test]
+    fn new_mut() {
+        let mut count = 0;
+        let pool = Arc::new(Pool::new_mut(Box::new(move || {
+            count += 1;
+            if count == 2 {
+                panic!("second value");
+            }
+            Dummy(count)
+        })));
+        assert_eq!(&Dummy(1), pool.get());
+        let pool2 = pool.clone();
+        assert!(thread::spawn(move || { pool2.get(); }).join().is_err());
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(3), pool2.get());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn clone() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = {
+            let count = count.clone();
+            Pool::with_fn(move || Dummy(count.fetch_add(1, SeqCst)))
+        };
+        assert_eq!(&Dummy(0), pool.get());
+        let clone = pool.clone();
+        assert_eq!(&Dummy(1), clone.get());
+        assert_eq!(&Dummy(0), pool.get());
+        assert_eq!(2, count.load(SeqCst));
+    }
+
+    #[test]
+    fn with_fn() {
+        let pool = Pool::with_fn(|| Dummy(5));
+        assert_eq!(&Dummy(5), pool.get());
+
+        let pool = Arc::new(Pool::with_fn(|| Dummy(7)));
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(7), pool2.get());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn get_mut() {
+        // This tests that the owner's value and another thread's value can be
+        // mutated independently.
+        let pool = Arc::new(Pool::new(dummy()));
+        unsafe { pool.get_mut().0 = 42; }
+        assert_eq!(&Dummy(42), pool.get());
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(1), pool2.get());
+            unsafe { pool2.get_mut().0 = 43; }
+            assert_eq!(&Dummy(43), pool2.get());
+        }).join().unwrap();
+        assert_eq!(&Dummy(42), pool.get());
+    }
+
+    #[test]
+    fn checkout() {
+        // This tests that a single thread can hold several distinct values
+        // at once, and that returned values are reused.
+        let pool = Pool::new(dummy());
+        {
+            let a = pool.checkout();
+            let mut b = pool.checkout();
+            assert_eq!(Dummy(1), *a);
+            assert_eq!(Dummy(2), *b);
+            b.0 = 42;
+        }
+        // `get` doesn't share values with `checkout`.
+        assert_eq!(&Dummy(0), pool.get());
+
+        // `b` was returned first, so `a` is on top of the free list.
+        let a = pool.checkout();
+        let b = pool.checkout();
+        let c = pool.checkout();
+        assert_eq!(Dummy(1), *a);
+        assert_eq!(Dummy(42), *b);
+        assert_eq!(Dummy(3), *c);
+    }
+
+    #[test]
+    fn checkout_many() {
+        let pool = Pool::with_fn(|| 0);
+        let mut guards = pool.checkout_many(3);
+        for (i, guard) in guards.iter_mut().enumerate() {
+            **guard = i + 1;
+        }
+        drop(guards);
+        let mut values: Vec<usize> =
+            pool.checkout_many(4).iter().map(|guard| **guard).collect();
+        values.sort();
+        assert_eq!(vec![0, 1, 2, 3], values);
+    }
+
+    #[test]
+    fn with_max_idle() {
+        let pool = Pool::with_max_idle(dummy(), 1);
+        let a = pool.checkout();
+        let b = pool.checkout();
+        assert_eq!((&Dummy(1), &Dummy(2)), (&*a, &*b));
+        drop(a);
+        drop(b);
+        // Only `a` was kept.
+        assert_eq!(&Dummy(1), &*pool.checkout());
+        let c = pool.checkout();
+        let d = pool.checkout();
+        assert_eq!((&Dummy(1), &Dummy(3)), (&*c, &*d));
+    }
+
+    #[test]
+    fn reclaim_idle() {
+        let pool = Pool::new(dummy());
+        let owner = pool.get() as *const Dummy;
+        let a = pool.checkout();
+        let b = pool.checkout();
+        assert_eq!((&Dummy(1), &Dummy(2)), (&*a, &*b));
+        drop(a);
+        drop(b);
+        pool.fill(1);
+        assert_eq!(3, pool.reclaim_idle());
+        assert_eq!(0, pool.reclaim_idle());
+        // The idle values are gone, but the owner keeps its value.
+        assert_eq!(&Dummy(4), &*pool.checkout());
+        assert_eq!(owner, pool.get() as *const Dummy);
+    }
+
+    #[test]
+    fn get_rc() {
+        let pool = Arc::new(Pool::new(dummy()));
+        let a = pool.get_rc();
+        let b = a.clone();
+        assert!(ptr::eq(&*a, &*b));
+        assert!(ptr::eq(&*a, pool.get()));
+        let weak = pool.downgrade();
+        drop(pool);
+        // The handles keep the pool alive.
+        assert!(weak.upgrade().is_some());
+        assert_eq!(&Dummy(0), &*b);
+        drop(a);
+        drop(b);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn with_max_idle_per_thread() {
+        let pool = Pool::with_max_idle_per_thread(dummy(), 3);
+        drop(pool.checkout_many(10));
+        assert_eq!(3, pool.free.lock().unwrap().len);
+        thread::scope(|s| {
+            s.spawn(|| drop(pool.checkout_many(2)));
+        });
+        assert_eq!(5, pool.free.lock().unwrap().len);
+    }
+
+    #[test]
+    fn checkout_array() {
+        let pool = Pool::new(dummy());
+        let [a, b] = pool.checkout_array();
+        assert_eq!((&Dummy(1), &Dummy(2)), (&*a, &*b));
+        drop((a, b));
+        let guards: [_; 3] = pool.checkout_array();
+        let mut values: Vec<usize> = guards.iter().map(|g| g.0).collect();
+        values.sort();
+        assert_eq!(vec![1, 2, 3], values);
+    }
+
+    #[test]
+    fn new_symmetric() {
+        let pool = Pool::new_symmetric(dummy());
+        assert_eq!(&Dummy(0), pool.get());
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(&Dummy(1), pool.get()));
+        });
+        assert!(!unsafe { pool.adopt() });
+        assert_eq!(None, pool.owner_thread_id());
+        assert_eq!(2, pool.len());
+        assert_eq!(None, pool.peek_owner());
+    }
+
+    #[test]
+    fn claim_ownership() {
+        thread_id::current();
+        let pool = Pool::new_symmetric(dummy());
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(&Dummy(0), pool.get());
+                assert!(!pool.is_owner());
+            });
+        });
+        assert!(pool.claim_ownership());
+        assert!(pool.claim_ownership());
+        assert!(pool.is_owner());
+        assert_eq!(&Dummy(1), pool.get());
+        thread::scope(|s| {
+            s.spawn(|| assert!(!pool.claim_ownership()));
+        });
+
+        let pool = Pool::new(dummy());
+        pool.get();
+        thread::scope(|s| {
+            s.spawn(|| assert!(!pool.claim_ownership()));
+        });
+
+        // A thread that already has a value in the map gets the owner's
+        // value once it claims the slot, and it has been created.
+        let pool = Pool::new_symmetric(dummy());
+        assert_eq!(&Dummy(0), pool.get());
+        assert_eq!(None, pool.peek_owner());
+        assert!(pool.claim_ownership());
+        assert_eq!(Some(&Dummy(1)), pool.peek_owner());
+        assert_eq!(&Dummy(1), pool.get());
+        assert_eq!(&Dummy(1), unsafe { pool.get_owner_unchecked() });
+    }
+
+    #[test]
+    fn swap_current_thread() {
+        thread_id::current();
+        let pool = Pool::new(dummy());
+        pool.get();
+        let old = unsafe { pool.swap_current_thread(Dummy(10)) };
+        assert_eq!(Some(Dummy(0)), old);
+        assert_eq!(&Dummy(10), pool.get());
+        thread::scope(|s| {
+            s.spawn(|| {
+                let old = unsafe { pool.swap_current_thread(Dummy(20)) };
+                assert_eq!(None, old);
+                assert_eq!(&Dummy(20), pool.get());
+                let old = unsafe { pool.swap_current_thread(Dummy(21)) };
+                assert_eq!(Some(Dummy(20)), old);
+                assert_eq!(&Dummy(21), pool.get());
+            });
+        });
+
+        // A thread with no value doesn't claim a free owner slot, so it
+        // never gets the value created up front instead of its own.
+        let pool = Pool::new(dummy());
+        let old = unsafe { pool.swap_current_thread(Dummy(10)) };
+        assert_eq!(None, old);
+        assert!(!pool.is_owner());
+        assert_eq!(&Dummy(10), pool.get());
+        assert_eq!(None, pool.owner_thread_id());
+    }
+
+    // This is meant to be run under ThreadSanitizer, which checks the
+    // accesses made by many threads for data races:
+    //
+    //     RUSTFLAGS=-Zsanitizer=thread cargo +nightly test -Zbuild-std \
+    //         --target x86_64-unknown-linux-gnu --lib stress
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn stress() {
+        thread_id::current();
+        let mut pool = Pool::new(Box::new(|| vec![0usize; 4]));
+        for round in 0..4 {
+            thread::scope(|s| {
+                for _ in 0..8 {
+                    s.spawn(|| {
+                        for i in 0..200 {
+                            assert_eq!(4, pool.get().len());
+                            // No reference to this thread's value is alive.
+                            unsafe { pool.get_mut()[0] = i };
+                            pool.checkout().push(i);
+                            if i % 50 == 0 {
+                                pool.bump_generation();
+                            }
+                        }
+                    });
+                }
+            });
+            assert!(pool.len() <= 9, "round {}", round);
+            pool.clear();
+        }
+    }
+
+    #[test]
+    fn detach() {
+        let pool = Pool::new(dummy());
+        assert_eq!(Dummy(1), pool.checkout().detach());
+        assert_eq!(0, pool.free.lock().unwrap().len);
+        assert_eq!(&Dummy(2), &*pool.checkout());
+    }
+
+    #[test]
+    fn with_creator_thread() {
+        let pool = Pool::with_creator_thread(|| thread::current().id());
+        let creator = *pool.get();
+        assert_ne!(thread::current().id(), creator);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| assert_eq!(&creator, pool.get()));
+            }
+        });
+
+        // `new` creates the owner's value right away.
+        let result = std::panic::catch_unwind(|| {
+            Pool::with_creator_thread(|| -> usize { panic!("no values") })
+        });
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn get_with_init() {
+        thread_id::current();
+        let inits = AtomicUsize::new(0);
+        let init = |_: &Dummy| {
+            inits.fetch_add(1, SeqCst);
+        };
+        let pool = Pool::new(dummy());
+        pool.get_with_init(init);
+        pool.get_with_init(init);
+        assert_eq!(1, inits.load(SeqCst));
+        let barrier = std::sync::Barrier::new(4);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    pool.get_with_init(init);
+                    pool.get_with_init(init);
+                    // Keep every thread alive, so that none of them inherits
+                    // the value of another.
+                    barrier.wait();
+                });
+            }
+        });
+        assert_eq!(5, inits.load(SeqCst));
+
+        // A new value is initialized after a bump, once.
+        pool.bump_generation();
+        pool.get_with_init(init);
+        pool.get_with_init(init);
+        assert_eq!(6, inits.load(SeqCst));
+
+        // The owner's value is initialized once, even if it was created up
+        // front and the owner slot was claimed with `claim_ownership`, and a
+        // value put in place by `swap_current_thread` is initialized like a
+        // new one.
+        let pool = Pool::new(dummy());
+        assert!(pool.claim_ownership());
+        pool.get_with_init(init);
+        pool.get_with_init(init);
+        assert_eq!(7, inits.load(SeqCst));
+        unsafe { pool.swap_current_thread(Dummy(10)) };
+        assert_eq!(&Dummy(10), pool.get_with_init(init));
+        pool.get_with_init(init);
+        assert_eq!(8, inits.load(SeqCst));
+    }
+
+    #[test]
+    fn drain_into() {
+        use std::cell::Cell;
+
+        thread_id::current();
+        let pool = Pool::new(Box::new(|| Cell::new(0)));
+        pool.get().set(1);
+        thread::scope(|s| {
+            for i in 2..6 {
+                let pool = &pool;
+                s.spawn(move || {
+                    let count = pool.get();
+                    for _ in 0..i {
+                        count.set(count.get() + 1);
+                    }
+                });
+            }
+        });
+        let mut counts = vec![];
+        pool.drain_into(|count| counts.push(count.get()));
+        // The owner's value comes first.
+        assert_eq!(1, counts[0]);
+        assert_eq!(1 + 2 + 3 + 4 + 5, counts.iter().sum::<usize>());
+    }
+
+    #[test]
+    fn group_by_mut() {
+        thread_id::current();
+        let mut pool = Pool::with_fn(|| (0, 0));
+        pool.get();
+        let barrier = std::sync::Barrier::new(5);
+        thread::scope(|s| {
+            for i in 0..5 {
+                let (pool, barrier) = (&pool, &barrier);
+                s.spawn(move || {
+                    unsafe { pool.get_mut().0 = i % 2 };
+                    barrier.wait();
+                });
+            }
+        });
+        let groups = pool.group_by_mut(|v| v.0);
+        assert_eq!(2, groups.len());
+        // The owner's key is 0 too.
+        assert_eq!(4, groups[&0].len());
+        assert_eq!(2, groups[&1].len());
+        for (_, values) in groups {
+            for v in values {
+                v.1 += 1;
+            }
+        }
+        assert_eq!(6, pool.iter_mut().map(|v| v.1).sum::<usize>());
+    }
+
+    #[test]
+    fn with_thread_capacity() {
+        let mut pool = Pool::with_thread_capacity(|| 0, 100);
+        // With `numa`, every node's shards have room for all of the threads.
+        #[cfg(not(feature = "numa"))]
+        let shards = SHARDS;
+        #[cfg(feature = "numa")]
+        let shards = SHARDS / super::numa::nodes();
+        for shard in &mut pool.global {
+            assert!(shard.get_mut().unwrap().capacity() >= 100 / shards);
+        }
+        assert!(pool.capacity() >= 100);
+        assert_eq!(0, Pool::new(dummy()).capacity());
+    }
+
+    #[test]
+    fn shrink_to_fit() {
+        let mut pool = Pool::new(dummy());
+        pool.get();
+        let guards: Vec<_> = (0..100).map(|_| pool.checkout()).collect();
+        drop(guards);
+        pool.fill(10);
+        pool.shrink_to_fit();
+        assert_eq!(1, pool.len());
+        assert_eq!(&Dummy(0), pool.get());
+        let (_, values) = pool.into_inner();
+        assert_eq!(110, values.len());
+    }
+
+    #[test]
+    fn buffer_pool() {
+        use super::buffer::BufferPool;
+
+        let pool = BufferPool::with_capacity(64);
+        let ptr = {
+            let mut buf = pool.acquire();
+            assert!(buf.is_empty());
+            assert!(buf.capacity() >= 64);
+            buf.extend_from_slice(b"stale");
+            buf.as_ptr()
+        };
+        let buf = pool.acquire();
+        assert!(buf.is_empty());
+        assert_eq!(ptr, buf.as_ptr());
+    }
+
+    #[test]
+    fn reserving_pool() {
+        use super::buffer::ReservingPool;
+
+        let pool = ReservingPool::<Vec<u32>>::with_capacity(8);
+        let ptr = {
+            let mut v = pool.acquire();
+            assert!(v.is_empty() && v.capacity() >= 8);
+            v.extend(0..100);
+            v.as_ptr()
+        };
+        let v = pool.acquire();
+        assert!(v.is_empty());
+        assert_eq!(ptr, v.as_ptr());
+
+        let pool = ReservingPool::<String>::with_capacity(32);
+        let ptr = {
+            let mut s = pool.acquire();
+            assert!(s.is_empty() && s.capacity() >= 32);
+            s.push_str("stale");
+            s.as_ptr()
+        };
+        let s = pool.acquire();
+        assert!(s.is_empty());
+        assert_eq!(ptr, s.as_ptr());
+    }
+
+    #[test]
+    fn secure_buffer_pool() {
+        use super::buffer::SecureBufferPool;
+
+        let pool = SecureBufferPool::with_capacity(16);
+        let ptr = {
+            let mut buf = pool.acquire();
+            buf.extend_from_slice(b"secret secret");
+            buf.truncate(6);
+            buf.as_ptr()
+        };
+        let mut buf = pool.acquire();
+        assert!(buf.is_empty());
+        assert_eq!(ptr, buf.as_ptr());
+        // Every byte of the buffer's capacity was written when it was
+        // scrubbed, so they can all be read back.
+        let cap = buf.capacity();
+        unsafe { buf.set_len(cap) };
+        assert!(buf.iter().all(|&b| b == 0));
+    }
+
+    #[test]
+    fn drop_order() {
+        use std::sync::Mutex;
+
+        struct Recorder(usize, Arc<Mutex<Vec<usize>>>);
+
+        impl Drop for Recorder {
+            fn drop(&mut self) {
+                self.1.lock().unwrap().push(self.0);
+            }
+        }
+
+        thread_id::current();
+        let dropped = Arc::new(Mutex::new(vec![]));
+        let pool = {
+            let dropped = dropped.clone();
+            let count = AtomicUsize::new(0);
+            Arc::new(Pool::new(Box::new(move || {
+                Recorder(count.fetch_add(1, SeqCst), dropped.clone())
+            })))
+        };
+        pool.get();
+        for _ in 0..2 {
+            let pool = pool.clone();
+            thread::spawn(move || { pool.get(); }).join().unwrap();
+        }
+        drop(pool.checkout());
+        assert!(dropped.lock().unwrap().is_empty());
+        drop(pool);
+        // The owner's value, 0, is dropped last.
+        let dropped = dropped.lock().unwrap();
+        assert_eq!(Some(&0), dropped.last());
+        assert_eq!(4, dropped.len());
+    }
+
+    #[test]
+    fn drop_panic() {
+        use std::panic::{AssertUnwindSafe, catch_unwind};
+        use std::sync::Mutex;
+
+        struct Recorder(usize, Arc<Mutex<Vec<usize>>>);
+
+        impl Drop for Recorder {
+            fn drop(&mut self) {
+                self.1.lock().unwrap().push(self.0);
+                if self.0 == 1 {
+                    panic!("dropping 1");
+                }
+            }
+        }
+
+        let dropped = Arc::new(Mutex::new(vec![]));
+        let pool = {
+            let dropped = dropped.clone();
+            let count = AtomicUsize::new(0);
+            Pool::new(Box::new(move || {
+                Recorder(count.fetch_add(1, SeqCst), dropped.clone())
+            }))
+        };
+        pool.get();
+        // The value that panics is the first one dropped from its shard,
+        // which holds two more values, and it's followed by another shard.
+        // The ids are far from any real thread's id.
+        let base = 1 << 20;
+        for id in [1, 1 + SHARDS, 1 + 2 * SHARDS, 2] {
+            unsafe { pool.get_with_id(base + id) };
+        }
+        assert!(catch_unwind(AssertUnwindSafe(|| drop(pool))).is_err());
+        // Every other value is still dropped, and the owner's value, 0, is
+        // still dropped last.
+        let mut dropped = dropped.lock().unwrap().clone();
+        assert_eq!(Some(0), dropped.pop());
+        dropped.sort();
+        assert_eq!(vec![1, 2, 3, 4], dropped);
+    }
+
+    #[test]
+    fn clear() {
+        let mut pool = Arc::new(Pool::new(dummy()));
+        assert_eq!(&Dummy(0), pool.get());
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(1), pool2.get());
+        }).join().unwrap();
+
+        Arc::get_mut(&mut pool).unwrap().clear();
+        assert_eq!(&Dummy(2), pool.get());
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(3), pool2.get());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn iter_mut() {
+        let mut pool = Arc::new(Pool::with_fn(|| 0));
+        assert_eq!(0, Arc::get_mut(&mut pool).unwrap().iter_mut().count());
+
+        let handles: Vec<_> = (1..=4).map(|n| {
+            let pool = pool.clone();
+            thread::spawn(move || unsafe { *pool.get_mut() += n })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        unsafe { *pool.get_mut() += 10 };
+
+        let pool = Arc::get_mut(&mut pool).unwrap();
+        assert_eq!(20, pool.iter_mut().map(|n| *n).sum::<usize>());
+    }
+
+    #[test]
+    fn into_inner() {
+        let pool = Arc::new(Pool::new(dummy()));
+        assert_eq!(&Dummy(0), pool.get());
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(1), pool2.get());
+            drop(pool2.checkout());
+        }).join().unwrap();
+
+        let pool = Arc::try_unwrap(pool).ok().unwrap();
+        let (owner, mut values) = pool.into_inner();
+        values.sort_by_key(|d| d.0);
+        assert_eq!(Some(Dummy(0)), owner);
+        assert_eq!(vec![Dummy(1), Dummy(2)], values);
+
+        let pool: Pool<usize, fn() -> usize> = Pool::new_const(|| 1);
+        assert_eq!((None, vec![]), pool.into_inner());
+    }
+
+    #[test]
+    fn for_each_mut() {
+        let mut pool = Arc::new(Pool::with_fn(|| 0));
+        pool.get();
+        let handles: Vec<_> = (0..3).map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || { pool.get(); })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+
+        let pool = Arc::get_mut(&mut pool).unwrap();
+        let mut visited = 0;
+        let flow = pool.for_each_mut(|v| {
+            visited += 1;
+            *v = visited;
+            if visited == 2 {
+                ControlFlow::Break(())
+            } else {
+                ControlFlow::Continue(())
+            }
+        });
+        assert!(flow.is_break());
+        assert_eq!(2, visited);
+        // The owner's value is visited first.
+        assert_eq!(1, *pool.get());
+    }
+
+    #[test]
+    fn get_dyn() {
+        trait Shape: Send {
+            fn sides(&self) -> usize;
+        }
+        struct Square;
+        impl Shape for Square {
+            fn sides(&self) -> usize { 4 }
+        }
+
+        let pool: Pool<Box<dyn Shape>> =
+            Pool::new(Box::new(|| Box::new(Square) as Box<dyn Shape>));
+        assert_eq!(4, pool.get_dyn().sides());
+        assert!(std::ptr::eq(pool.get_dyn(), &**pool.get()));
+    }
+
+    #[test]
+    fn with_reset() {
+        let pool = Arc::new(Pool::with_reset(
+            Vec::new,
+            Box::new(|v: &mut Vec<u8>| v.clear()),
+        ));
+        unsafe {
+            pool.get_mut().extend_from_slice(b"owner");
+            assert!(pool.get_mut().is_empty());
+        }
+        let pool2 = pool.clone();
+        thread::spawn(move || unsafe {
+            pool2.get_mut().extend_from_slice(b"other");
+            assert!(pool2.get_mut().is_empty());
+        }).join().unwrap();
+
+        pool.checkout().extend_from_slice(b"checkout");
+        assert!(pool.checkout().is_empty());
+    }
+
+    #[test]
+    fn reset_all() {
+        let reset = Box::new(|v: &mut Vec<u8>| v.clear());
+        let mut pool = Arc::new(Pool::with_reset(|| vec![1u8; 8], reset));
+        pool.get();
+        let pool2 = pool.clone();
+        thread::spawn(move || { pool2.get(); }).join().unwrap();
+
+        let pool = Arc::get_mut(&mut pool).unwrap();
+        assert_eq!(2, pool.iter_mut().filter(|v| v.len() == 8).count());
+        pool.reset_all();
+        assert!(pool.iter_mut().all(|v| v.is_empty()));
+    }
+
+    #[test]
+    fn default() {
+        let pool: Arc<Pool<Vec<u8>>> = Arc::new(Pool::default());
+        assert!(pool.get().is_empty());
+        unsafe { pool.get_mut().push(5) };
+        assert_eq!(&[5], &**pool.get());
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert!(pool2.get().is_empty());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn new_const_is_lazy() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        fn create() -> usize {
+            CREATED.fetch_add(1, Ordering::SeqCst)
+        }
+        static POOL: Pool<usize, fn() -> usize> = Pool::new_const(create);
+
+        assert_eq!(0, CREATED.load(Ordering::SeqCst));
+        assert_eq!(0, POOL.len());
+        let owner = *POOL.get();
+        assert_eq!(1, CREATED.load(Ordering::SeqCst));
+        assert_eq!(owner, *POOL.get());
+        let other = thread::spawn(|| *POOL.get()).join().unwrap();
+        assert_ne!(owner, other);
+        assert_eq!(2, CREATED.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn debug() {
+        struct NotDebug;
+
+        #[derive(Debug)]
+        #[allow(dead_code)]
+        struct Outer {
+            pool: Pool<NotDebug>,
+        }
+
+        let outer = Outer { pool: Pool::new(Box::new(|| NotDebug)) };
+        assert_eq!(
+            "Outer { pool: Pool { owned: false, len: 0 } }",
+            format!("{:?}", outer),
+        );
+        outer.pool.get();
+        assert_eq!(
+            "Pool { owned: true, len: 1 }",
+            format!("{:?}", outer.pool),
+        );
+    }
+
+    #[test]
+    fn new_lazy() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = {
+            let count = count.clone();
+            Pool::new_lazy(Box::new(move || count.fetch_add(1, SeqCst)))
+        };
+        assert_eq!(0, count.load(SeqCst));
+        assert_eq!(None, pool.peek_owner());
+        assert_eq!(&0, pool.get());
+        assert_eq!(&0, pool.get());
+        assert_eq!(1, count.load(SeqCst));
+    }
+
+    #[test]
+    fn with_owner() {
+        thread_id::current();
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = {
+            let count = count.clone();
+            Pool::with_owner(
+                Box::new(move || count.fetch_add(1, SeqCst) + 10),
+                5,
+            )
+        };
+        assert!(!pool.is_owner());
+        assert_eq!(0, count.load(SeqCst));
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(&5, pool.get()));
+        });
+        assert_eq!(&10, pool.get());
+        assert_eq!(1, count.load(SeqCst));
+    }
+
+    #[test]
+    fn thread_ids() {
+        let pool = Pool::new(dummy());
+        assert!(pool.thread_ids().is_empty());
+        assert_eq!(None, pool.owner_thread_id());
+        unsafe { pool.get_with_id(7) };
+        unsafe { pool.get_with_id(3) };
+        unsafe { pool.get_with_id(5) };
+        assert_eq!(vec![3, 5, 7], pool.thread_ids());
+        assert_eq!(Some(7), pool.owner_thread_id());
+    }
+
+    #[test]
+    fn with_finalizer() {
+        use std::sync::Mutex;
+
+        use super::FinalizeFn;
+
+        let finalized = Arc::new(Mutex::new(vec![]));
+        let finalize: FinalizeFn<usize> = {
+            let finalized = finalized.clone();
+            Box::new(move |n| finalized.lock().unwrap().push(n))
+        };
+        let count = AtomicUsize::new(0);
+        let mut pool = Pool::with_finalizer(
+            || count.fetch_add(1, SeqCst),
+            finalize,
+        );
+        pool.get();
+        unsafe { pool.get_with_id(usize::MAX) };
+        pool.clear();
+        finalized.lock().unwrap().sort();
+        assert_eq!(vec![0, 1], *finalized.lock().unwrap());
+
+        pool.get();
+        pool.max_idle = 0;
+        drop(pool.checkout());
+        assert_eq!(vec![0, 1, 3], *finalized.lock().unwrap());
+        drop(pool);
+        assert_eq!(vec![0, 1, 3, 2], *finalized.lock().unwrap());
+
+        finalized.lock().unwrap().clear();
+        let count = AtomicUsize::new(0);
+        let finalize: FinalizeFn<usize> = {
+            let finalized = finalized.clone();
+            Box::new(move |n| finalized.lock().unwrap().push(n))
+        };
+        let pool = Pool::with_finalizer(
+            || count.fetch_add(1, SeqCst),
+            finalize,
+        );
+        assert_eq!(&0, pool.get());
+        thread::scope(|s| {
+            s.spawn(|| {
+                assert_eq!(&1, pool.get());
+                assert!(!pool.is_owner());
+            });
+        });
+        drop(pool);
+        assert_eq!(vec![1, 0], *finalized.lock().unwrap());
+    }
+
+    #[test]
+    fn keyed_pool() {
+        use keyed::KeyedPool;
+
+        let count = AtomicUsize::new(0);
+        let pool = KeyedPool::with_fn(|key: &usize| {
+            count.fetch_add(1, SeqCst);
+            key * 10
+        });
+        let first = pool.get(&1);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for key in 0..50 {
+                        assert_eq!(&(key * 10), pool.get(&key));
+                    }
+                });
+            }
+        });
+        assert!(std::ptr::eq(first, pool.get(&1)));
+        assert_eq!(50, pool.len());
+        assert!(count.load(SeqCst) >= 50);
+        let values = pool.into_inner();
+        assert_eq!(Some(&490), values.get(&49));
+    }
+
+    #[test]
+    fn total_allocated() {
+        let pool = Pool::new(Box::new(|| vec![0u32; 4]));
+        assert_eq!(16, pool.total_allocated());
+        unsafe { pool.get_with_id(usize::MAX - 1) };
+        unsafe { pool.get_with_id(usize::MAX) };
+        pool.fill(2);
+        drop(pool.checkout());
+        assert_eq!(64, pool.total_allocated());
+
+        // A panic in `pool_size` doesn't poison the locks of the pool.
+        struct Sized(usize);
+        impl super::PoolSized for Sized {
+            fn pool_size(&self) -> usize {
+                assert_eq!(0, self.0, "pool_size");
+                0
+            }
+        }
+        let count = AtomicUsize::new(0);
+        let mut pool = Pool::with_fn(|| Sized(count.fetch_add(1, SeqCst)));
+        pool.get();
+        assert_eq!(1, unsafe { pool.get_with_id(usize::MAX) }.0);
+        let result = std::panic::catch_unwind(
+            std::panic::AssertUnwindSafe(|| pool.total_allocated()),
+        );
+        assert!(result.is_err());
+        assert_eq!(1, unsafe { pool.get_with_id(usize::MAX) }.0);
+        assert_eq!(2, pool.iter_mut().count());
+        pool.shrink_to_fit();
+        pool.clear();
+    }
+
+    #[test]
+    fn from_values() {
+        let values = vec![
+            (0, Dummy(10)),
+            (3, Dummy(11)),
+            (3, Dummy(12)),
+            (5, Dummy(13)),
+        ];
+        let pool = Pool::from_values(dummy(), values);
+        assert_eq!(vec![3, 5], pool.thread_ids());
+        assert_eq!(&Dummy(0), unsafe { pool.get_with_id(4) });
+        assert_eq!(&Dummy(11), unsafe { pool.get_with_id(3) });
+        assert_eq!(&Dummy(13), unsafe { pool.get_with_id(5) });
+        assert_eq!(&Dummy(1), unsafe { pool.get_with_id(6) });
+    }
+
+    #[test]
+    #[cfg(feature = "numa")]
+    fn numa() {
+        use super::numa;
+
+        assert_eq!(Some(0), numa::max_node("0"));
+        assert_eq!(Some(3), numa::max_node("0-3"));
+        assert_eq!(Some(5), numa::max_node("0,2-5"));
+        assert_eq!(None, numa::max_node(""));
+        assert!(numa::is_local(3, (1, 2)));
+        assert!(!numa::is_local(SHARDS + 2, (1, 2)));
+
+        // Every thread gets an id in one of its node's shards.
+        let (node, nodes) = numa::current();
+        assert!(node < nodes);
+        let ids: Vec<usize> = thread::scope(|s| {
+            let threads: Vec<_> = (0..4)
+                .map(|_| s.spawn(|| (thread_id::current(), numa::current())))
+                .collect();
+            threads.into_iter().map(|t| t.join().unwrap()).map(|(id, node)| {
+                assert!(numa::is_local(id, node));
+                id
+            }).collect()
+        });
+        assert_eq!(4, ids.len());
+    }
+
+    #[test]
+    #[cfg(feature = "debug-tracking")]
+    fn get_tracked() {
+        use std::panic::{AssertUnwindSafe, catch_unwind};
+
+        let pool = Pool::new(dummy());
+        let other = Pool::new(dummy());
+        let value = pool.get_tracked();
+        let _other = other.get_tracked();
+        let remove = || unsafe { pool.remove_current_thread() };
+        assert!(catch_unwind(AssertUnwindSafe(remove)).is_err());
+        let swap = || unsafe { pool.swap_current_thread(Dummy(5)) };
+        assert!(catch_unwind(AssertUnwindSafe(swap)).is_err());
+        assert_eq!(&Dummy(0), &*value);
+        drop(value);
+        assert_eq!(Some(Dummy(0)), remove());
+    }
+
+    #[test]
+    fn cached_non_owner() {
+        // Assign this thread's id first, so that it doesn't inherit the id
+        // of the owner when it exits.
+        thread_id::current();
+        let mut pool = Pool::new(dummy());
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(&Dummy(0), pool.get()));
+        });
+        assert_eq!(&Dummy(1), pool.get());
+        assert_eq!(&Dummy(1), pool.get());
+
+        // Another pool doesn't see this thread's cached value.
+        let other = Pool::new(dummy());
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(&Dummy(0), other.get()));
+        });
+        assert_eq!(&Dummy(1), other.get());
+        assert_eq!(&Dummy(1), pool.get());
+
+        // Clearing the pool invalidates this thread's cached value.
+        pool.clear();
+        thread::scope(|s| {
+            s.spawn(|| assert_eq!(&Dummy(2), pool.get()));
+        });
+        assert_eq!(&Dummy(3), pool.get());
     }
 
     #[test]
-    fn empty() {
+    fn contains_current_thread() {
+        let pool = Arc::new(Pool::new(dummy()));
+        assert!(!pool.contains_current_thread());
+        pool.get();
+        assert!(pool.contains_current_thread());
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert!(!pool2.contains_current_thread());
+            pool2.get();
+            assert!(pool2.contains_current_thread());
+        }).join().unwrap();
+        assert_eq!(2, pool.len());
+    }
+
+    #[test]
+    fn get_existing() {
+        let pool = Arc::new(Pool::new(dummy()));
+        assert_eq!(None, pool.get_existing());
+        pool.prewarm();
+        assert_eq!(Some(&Dummy(0)), pool.get_existing());
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(None, pool2.get_existing());
+            pool2.prewarm();
+            assert_eq!(Some(&Dummy(1)), pool2.get_existing());
+            assert_eq!(Some(&Dummy(1)), pool2.get_existing());
+        }).join().unwrap();
+        assert_eq!(2, pool.len());
+    }
+
+    #[test]
+    fn local_pool() {
+        let pool = LocalPool::new(Box::new(|| Rc::new(RefCell::new(0))));
+        *pool.get().borrow_mut() += 1;
+        assert_eq!(1, *pool.get().borrow());
+        assert_eq!(1, *pool.into_inner().unwrap().borrow());
+
+        // A pool of `Send` values can be moved to another thread, but it
+        // won't serve it.
+        let pool = LocalPool::with_fn(|| 5);
+        assert!(thread::spawn(move || { pool.get(); }).join().is_err());
+    }
+
+    #[test]
+    fn map() {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+
+        let pool = Pool::with_fn(|| Dummy(COUNT.fetch_add(1, SeqCst)));
+        assert_eq!(&Dummy(0), pool.get());
+        let mapped = pool.map(|d| d.0 * 10);
+        assert_eq!(&10, mapped.get());
+        assert_eq!(&10, mapped.get());
+        // The source's cached value isn't touched.
+        assert_eq!(&Dummy(0), pool.get());
+        assert_eq!(1, pool.len());
+        // The mapped pool doesn't borrow the source.
+        drop(pool);
+        assert_eq!(&10, mapped.get());
+    }
+
+    #[test]
+    fn peek_owner() {
+        thread_id::current();
+        let pool = Arc::new(Pool::new(dummy()));
+        assert_eq!(Some(&Dummy(0)), pool.peek_owner());
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            // This thread becomes the owner.
+            assert_eq!(&Dummy(0), pool2.get());
+        }).join().unwrap();
+        assert_eq!(Some(&Dummy(0)), pool.peek_owner());
+        assert_eq!(&Dummy(1), pool.get());
+
+        let pool = Pool::new_const(|| 5);
+        assert_eq!(None, pool.peek_owner());
+        pool.get();
+        assert_eq!(Some(&5), pool.peek_owner());
+    }
+
+    #[test]
+    fn get_or_create_with() {
+        let pool = Arc::new(Pool::new_const(|| 0));
+        pool.fill(1);
+        assert_eq!(&5, pool.get_or_create_with(|| 5));
+        assert_eq!(&5, pool.get_or_create_with(|| unreachable!()));
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&6, pool2.get_or_create_with(|| 6));
+            assert_eq!(&6, pool2.get());
+        }).join().unwrap();
+        assert_eq!(&5, pool.get());
+    }
+
+    #[test]
+    fn remove_current_thread() {
+        let pool = Arc::new(Pool::new(dummy()));
+        assert_eq!(&Dummy(0), pool.get());
+        assert_eq!(Some(Dummy(0)), unsafe { pool.remove_current_thread() });
+        assert_eq!(None, unsafe { pool.remove_current_thread() });
+        assert_eq!(&Dummy(1), pool.get());
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(2), pool2.get());
+            assert_eq!(&Dummy(2), pool2.get());
+            assert_eq!(Some(Dummy(2)), unsafe {
+                pool2.remove_current_thread()
+            });
+            assert!(!pool2.contains_current_thread());
+            assert_eq!(&Dummy(3), pool2.get());
+        }).join().unwrap();
+        assert_eq!(2, pool.len());
+        assert_eq!(&Dummy(1), pool.get());
+    }
+
+    #[test]
+    fn is_owner() {
+        thread_id::current();
+        let pool = Arc::new(Pool::new(dummy()));
+        assert!(!pool.is_owner());
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            pool2.get();
+            assert!(pool2.is_owner());
+        }).join().unwrap();
+        pool.get();
+        assert!(!pool.is_owner());
+    }
+
+    #[test]
+    fn get_nonblocking() {
+        let pool = Arc::new(Pool::new_const(|| 5));
+        assert_eq!(None, pool.get_nonblocking());
+        pool.prewarm();
+        assert_eq!(Some(&5), pool.get_nonblocking());
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            pool2.prewarm();
+            // Nothing is cached after `prewarm`, so this looks in the shard.
+            let id = thread_id::current();
+            thread_id::cache(0, 0, std::ptr::null_mut());
+            assert_eq!(Some(&5), pool2.get_nonblocking());
+            let _lock = pool2.shard(id).lock().unwrap();
+            assert_eq!(None, pool2.get_nonblocking());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn downgrade() {
+        let pool = Arc::new(Pool::new(dummy()));
+        let weak = pool.downgrade();
+        assert_eq!(&Dummy(0), weak.upgrade().unwrap().get());
+        let weak2 = weak.clone();
+        let got = thread::spawn(move || {
+            weak2.upgrade().map(|pool| pool.get().0)
+        }).join().unwrap();
+        assert_eq!(Some(1), got);
+        drop(pool);
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn cleanup_on_thread_exit() {
+        let pool = Arc::new(Pool::new(dummy()));
+        pool.get();
+        let handles: Vec<_> = (0..4).map(|_| {
+            let pool = pool.clone();
+            thread::spawn(move || {
+                unsafe { pool.cleanup_on_thread_exit() };
+                pool.get();
+            })
+        }).collect();
+        for h in handles {
+            h.join().unwrap();
+        }
+        assert_eq!(1, pool.len());
+
+        // The callback doesn't keep the pool alive.
+        let pool = Arc::new(Pool::new(dummy()));
+        let weak = pool.downgrade();
+        let (tx, rx) = std::sync::mpsc::channel();
+        let handle = thread::spawn(move || {
+            let pool: Arc<Pool<Dummy>> = rx.recv().unwrap();
+            unsafe { pool.cleanup_on_thread_exit() };
+            pool.get();
+        });
+        tx.send(pool).unwrap();
+        handle.join().unwrap();
+        assert!(weak.upgrade().is_none());
+    }
+
+    #[test]
+    fn get_during_thread_teardown() {
+        use std::cell::RefCell;
+        use std::sync::mpsc::{Sender, channel};
+
+        struct Late(Arc<Pool<Dummy>>, Sender<usize>);
+
+        impl Drop for Late {
+            fn drop(&mut self) {
+                self.1.send(self.0.get() as *const Dummy as usize).unwrap();
+            }
+        }
+
+        thread_local!(static LATE: RefCell<Option<Late>> = const {
+            RefCell::new(None)
+        });
+
+        thread_id::current();
+        let pool = Arc::new(Pool::new(dummy()));
+        pool.get();
+        let (tx, rx) = channel();
+        for _ in 0..2 {
+            let (pool, tx) = (pool.clone(), tx.clone());
+            thread::spawn(move || {
+                // This thread local is initialized before the thread's id,
+                // so it's likely to be destroyed after it.
+                let late = Late(pool.clone(), tx);
+                LATE.with(|cell| *cell.borrow_mut() = Some(late));
+                pool.get();
+            }).join().unwrap();
+        }
+        drop(tx);
+        let late: Vec<usize> = rx.iter().collect();
+        assert_eq!(2, late.len());
+
+        // The next thread is likely to take the id of the last one. Either
+        // way, it must not get the value that the destructor got, since both
+        // might use it at once.
+        let pool2 = pool.clone();
+        let next = thread::spawn(move || {
+            pool2.get() as *const Dummy as usize
+        }).join().unwrap();
+        assert!(!late.contains(&next));
+    }
+
+    #[test]
+    fn get_after_cleanup_on_thread_exit() {
+        use std::cell::RefCell;
+        use std::sync::mpsc::{Sender, channel};
+
+        struct Late(Arc<Pool<Dummy>>, Sender<usize>);
+
+        impl Drop for Late {
+            fn drop(&mut self) {
+                self.1.send(self.0.get().0).unwrap();
+            }
+        }
+
+        thread_local!(static LATE: RefCell<Option<Late>> = const {
+            RefCell::new(None)
+        });
+
+        thread_id::current();
+        let pool = Arc::new(Pool::new(dummy()));
+        pool.get();
+        let (tx, rx) = channel();
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            // As in `get_during_thread_teardown`, this is likely to be
+            // destroyed after the callback removed the thread's value.
+            let late = Late(pool2.clone(), tx);
+            LATE.with(|cell| *cell.borrow_mut() = Some(late));
+            unsafe { pool2.cleanup_on_thread_exit() };
+            assert_eq!(&Dummy(1), pool2.get());
+        }).join().unwrap();
+        // The destructor got a new value rather than the removed one.
+        assert_eq!(2, rx.recv().unwrap());
+    }
+
+    #[test]
+    fn fill() {
+        let count = Arc::new(AtomicUsize::new(0));
+        let pool = {
+            let count = count.clone();
+            Arc::new(Pool::new_const(move || count.fetch_add(1, SeqCst)))
+        };
+        pool.fill(3);
+        assert_eq!(3, count.load(SeqCst));
+        assert_eq!(0, pool.len());
+
+        pool.prewarm();
+        let pool2 = pool.clone();
+        thread::spawn(move || { pool2.get(); }).join().unwrap();
+        let a = pool.checkout();
+        assert_eq!(3, count.load(SeqCst));
+        let b = pool.checkout();
+        assert_eq!((0, 3), (*a, *b));
+        assert_eq!(4, count.load(SeqCst));
+    }
+
+    #[test]
+    #[cfg(feature = "stats")]
+    fn stats() {
+        use super::PoolStats;
+
+        thread_id::current();
+        let pool = Arc::new(Pool::new(dummy()));
+        pool.get();
+        pool.get();
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            pool2.get();
+            pool2.get();
+            pool2.get();
+        }).join().unwrap();
+        // The owner's value was created by `new`, so only the other thread
+        // misses.
+        let expected = PoolStats { hits: 4, misses: 1, threads: 2 };
+        assert_eq!(expected, pool.stats());
+    }
+
+    #[test]
+    fn pool_macro() {
+        pool! {
+            static ONE: usize = || 1;
+            /// A pool with attributes.
+            #[allow(dead_code)]
+            pub(crate) static TWO: String = || "two".to_string();
+        }
+        assert_eq!(1, *ONE.get());
+        assert_eq!("two", *TWO.get());
+    }
+
+    #[test]
+    fn len() {
+        let pool = Arc::new(Pool::new(dummy()));
+        assert_eq!(0, pool.len());
+        assert!(pool.is_empty());
+
+        pool.get();
+        assert_eq!(1, pool.len());
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            pool2.get();
+            pool2.get();
+        }).join().unwrap();
+        assert_eq!(2, pool.len());
+        assert!(!pool.is_empty());
+    }
+
+    #[test]
+    fn bump_generation() {
+        thread_id::current();
+        let pool = Arc::new(Pool::new(dummy()));
+        let old = pool.get();
+        assert_eq!(&Dummy(0), old);
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(1), pool2.get());
+            let old = pool2.get();
+            pool2.bump_generation();
+            assert_eq!(&Dummy(2), pool2.get());
+            assert_eq!(&Dummy(2), pool2.get());
+            assert_eq!(&Dummy(1), old);
+        }).join().unwrap();
+        assert_eq!(2, pool.generation());
+
+        // The owner's stale value is still alive, but it gets a new one.
+        assert!(!pool.contains_current_thread());
+        assert_eq!(&Dummy(3), pool.get());
+        assert_eq!(&Dummy(3), pool.get());
+        assert_eq!(&Dummy(0), old);
+        assert!(pool.contains_current_thread());
+        assert_eq!(2, pool.len());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn thread_exit_keeps_values() {
+        // This tests that the values of threads that have exited are never
+        // handed to another thread, since the exited thread's thread local
+        // destructors may still be using them, and neither are their ids.
+        let pool = Arc::new(Pool::new(dummy()));
+        let (mut ids, mut vals) = (HashSet::new(), HashSet::new());
+        for _ in 0..10 {
+            let handles: Vec<_> = (0..4).map(|_| {
+                let pool = pool.clone();
+                thread::spawn(move || (thread_id::current(), pool.get().0))
+            }).collect();
+            for h in handles {
+                let (id, val) = h.join().unwrap();
+                assert!(ids.insert(id), "id reused: {}", id);
+                assert!(vals.insert(val), "value reused: {}", val);
+            }
+        }
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn thread_ids_recycled() {
+        // This tests that short lived threads reuse ids instead of allocating
+        // new ones. Other tests may be running concurrently, so this only
+        // checks that the counter grows far less than the number of threads.
+        let before = COUNTER.load(SeqCst);
+        for _ in 0..1000 {
+            thread::spawn(|| { thread_id::current(); }).join().unwrap();
+        }
+        let after = COUNTER.load(SeqCst);
+        assert!(after - before < 100, "ids allocated: {}", after - before);
+    }
+
+    #[test]
+    fn adopt() {
+        let pool = Arc::new(Pool::new(dummy()));
+        assert!(unsafe { pool.adopt() });
+        assert!(unsafe { pool.adopt() });
+        assert_eq!(&Dummy(0), pool.get());
+
+        // A live owner can't be displaced.
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert!(!unsafe { pool2.adopt() });
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn adopt_exited_owner() {
+        // The owner's id is never reused by another thread, so once it has
+        // exited, its value can always be adopted. The owner's thread has
+        // been joined, so nothing can still be using its value.
+        let pool = Arc::new(Pool::new(dummy()));
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(0), pool2.get());
+        }).join().unwrap();
+        assert_eq!(&Dummy(1), pool.get());
+        assert!(unsafe { pool.adopt() });
+        assert_eq!(&Dummy(0), pool.get());
+    }
+
+    #[test]
+    fn get_with_id() {
         let pool = Pool::new(dummy());
-        assert_eq!(&Dummy(0), &*pool.get());
+        unsafe {
+            assert_eq!(&Dummy(0), pool.get_with_id(5));
+            assert_eq!(&Dummy(1), pool.get_with_id(6));
+            assert_eq!(&Dummy(0), pool.get_with_id(5));
+            assert_eq!(&Dummy(1), pool.get_with_id(6));
+        }
     }
 
     #[test]
-    fn reuse() {
-        // This tests that multiple accesses from the same thread don't create
-        // new values.
+    fn get_from() {
+        use std::cell::Cell;
+
+        use super::{CurrentThread, ThreadIdSource};
+
+        // A single thread pretending to be two threads, taking turns.
+        struct Turns(Cell<usize>);
+
+        unsafe impl ThreadIdSource for Turns {
+            fn current_id(&self) -> usize {
+                let id = self.0.get();
+                self.0.set(3 - id);
+                id
+            }
+        }
+
         let pool = Pool::new(dummy());
-        {
-            assert_eq!(&Dummy(0), &*pool.get());
+        let turns = Turns(Cell::new(1));
+        assert_eq!(&Dummy(0), pool.get_from(&turns));
+        assert_eq!(&Dummy(1), pool.get_from(&turns));
+        assert_eq!(&Dummy(0), pool.get_from(&turns));
+        assert_eq!(&Dummy(1), pool.get_from(&turns));
+
+        let pool = Pool::new(dummy());
+        assert_eq!(pool.get(), pool.get_from(&CurrentThread));
+    }
+
+    #[test]
+    fn ids_near_overflow() {
+        use std::panic::catch_unwind;
+
+        use super::ThreadIdSource;
+
+        // A source that hands out a new id on every call, like a thread that
+        // exits after every call, with a counter that's about to overflow.
+        struct Fresh(AtomicUsize);
+
+        unsafe impl ThreadIdSource for Fresh {
+            fn current_id(&self) -> usize {
+                thread_id::next(&self.0)
+            }
         }
-        assert_eq!(&Dummy(0), &*pool.get());
-        assert_eq!(&Dummy(0), &*pool.get());
+
+        let pool = Pool::new(dummy());
+        let fresh = Fresh(AtomicUsize::new(usize::MAX - 2));
+        assert_eq!(&Dummy(0), pool.get_from(&fresh));
+        assert_eq!(&Dummy(1), pool.get_from(&fresh));
+        assert_eq!(vec![usize::MAX - 1, usize::MAX], pool.thread_ids());
+        assert!(catch_unwind(|| fresh.current_id()).is_err());
+        assert!(catch_unwind(|| fresh.current_id()).is_err());
     }
 
     #[test]
-    fn no_reuse() {
-        // This tests that a pool's values aren't shared between threads.
-        // i.e., the init function is called when another thread tries to
-        // get a value.
-        let pool = Arc::new(Pool::new(dummy()));
-        let val = pool.get();
-        assert_eq!(&Dummy(0), &*val);
+    fn values_never_move() {
+        // This tests that references remain valid while a shard grows.
+        let pool = Pool::new(dummy());
+        let ids = (1..100).map(|i| i * SHARDS);
+        let vals: Vec<&Dummy> =
+            ids.clone().map(|id| unsafe { pool.get_with_id(id) }).collect();
+        for (i, (id, val)) in ids.zip(vals).enumerate() {
+            assert_eq!(&Dummy(i), val);
+            assert_eq!(val as *const _, unsafe { pool.get_with_id(id) });
+        }
+    }
+
+    #[test]
+    fn create_panic_does_not_poison() {
+        use std::sync::atomic::AtomicBool;
+
+        let panic = Arc::new(AtomicBool::new(false));
+        let pool = {
+            let panic = panic.clone();
+            let count = AtomicUsize::new(0);
+            Arc::new(Pool::new(Box::new(move || {
+                if panic.load(SeqCst) {
+                    panic!("creation failed");
+                }
+                Dummy(count.fetch_add(1, SeqCst))
+            })))
+        };
+        assert_eq!(&Dummy(0), pool.get());
+
+        panic.store(true, SeqCst);
+        let pool2 = pool.clone();
+        assert!(thread::spawn(move || { pool2.get(); }).join().is_err());
+
+        panic.store(false, SeqCst);
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            assert_eq!(&Dummy(1), pool2.get());
+        }).join().unwrap();
+        assert_eq!(2, pool.len());
+    }
+
+    #[test]
+    #[cfg(not(feature = "parking_lot"))]
+    fn get_checked() {
+        use std::panic::{self, AssertUnwindSafe};
+        use std::sync::atomic::AtomicBool;
+
+        use super::PoolError;
+
+        let fail = Arc::new(AtomicBool::new(false));
+        let pool = {
+            let fail = fail.clone();
+            Arc::new(Pool::new(Box::new(move || {
+                if fail.load(SeqCst) {
+                    panic!("creation failed");
+                }
+                5
+            })))
+        };
+        assert_eq!(Ok(&5), pool.get_checked());
+
+        let pool2 = pool.clone();
+        thread::spawn(move || {
+            fail.store(true, SeqCst);
+            assert_eq!(Err(PoolError::CreateFailed), pool2.get_checked());
+            fail.store(false, SeqCst);
+
+            let id = thread_id::current();
+            let _ = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _lock = pool2.shard(id).lock().unwrap();
+                panic!("poison");
+            }));
+            assert_eq!(Err(PoolError::Poisoned), pool2.get_checked());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn reentrant_create() {
+        // This tests that the initialization function may use the pool
+        // itself, on both the owner's path and the slow path, without
+        // deadlocking. The value created by the innermost call wins.
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        static POOL: Pool<usize, fn() -> usize> = Pool::new_const(create);
+        fn create() -> usize {
+            let n = CREATED.fetch_add(1, SeqCst);
+            if n & 1 == 0 {
+                assert_eq!(n + 1, *POOL.get());
+            }
+            n
+        }
+
+        assert_eq!(1, *POOL.get());
+        thread::spawn(|| assert_eq!(3, *POOL.get())).join().unwrap();
+        assert_eq!(4, CREATED.load(SeqCst));
+    }
+
+    #[test]
+    fn reentrant_create_finalizes() {
+        // This tests that the values created by the outer calls in
+        // `reentrant_create` are passed to the finalizer, since they're
+        // discarded, on both the owner's path and the slow path.
+        use std::sync::{Mutex, OnceLock};
+
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        static FINALIZED: Mutex<Vec<usize>> = Mutex::new(Vec::new());
+        static POOL: OnceLock<Pool<usize>> = OnceLock::new();
+
+        let mut pool: Pool<usize> = Pool::new_lazy(Box::new(|| {
+            let n = CREATED.fetch_add(1, SeqCst);
+            if n & 1 == 0 {
+                assert_eq!(n + 1, *POOL.get().unwrap().get());
+            }
+            n
+        }));
+        pool.finalize = Some(Arc::new(|n| FINALIZED.lock().unwrap().push(n)));
+        assert!(POOL.set(pool).is_ok());
+
+        let pool = || POOL.get().unwrap();
+        assert_eq!(1, *pool().get());
+        thread::spawn(move || assert_eq!(3, *pool().get())).join().unwrap();
+        assert_eq!(vec![0, 2], *FINALIZED.lock().unwrap());
+    }
+
+    #[test]
+    fn current_thread_id() {
+        let pool = Pool::new(dummy());
+        let id = super::current_thread_id();
+        assert_ne!(0, id);
+        assert_eq!(id, super::current_thread_id());
+        assert_eq!(&Dummy(0), pool.get());
+        assert_eq!(&Dummy(0), unsafe { pool.get_with_id(id) });
+
+        let other = thread::spawn(super::current_thread_id).join().unwrap();
+        assert_ne!(id, other);
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    fn task_values() {
+        use std::future::{Future, poll_fn};
+        use std::pin::pin;
+        use std::task::{Context, Poll, Waker};
+
+        use super::task;
+
+        fn poll_now<F: Future>(f: std::pin::Pin<&mut F>) -> F::Output {
+            match f.poll(&mut Context::from_waker(Waker::noop())) {
+                Poll::Ready(output) => output,
+                Poll::Pending => panic!("future isn't ready"),
+            }
+        }
+
+        let pool = Pool::new(dummy());
+        let get = || poll_fn(|_| Poll::Ready(unsafe { pool.get_task() }.0));
+        // Both tasks exist at the same time on this thread, but get their
+        // own values, which are distinct from this thread's value.
+        let mut a = pin!(task::scope(get()));
+        let mut b = pin!(task::scope(get()));
+        assert_eq!(0, poll_now(a.as_mut()));
+        assert_eq!(1, poll_now(b.as_mut()));
+        assert_eq!(&Dummy(2), pool.get());
+        assert_eq!(None, task::current_task_id());
+    }
+
+    #[test]
+    #[cfg(feature = "tokio")]
+    #[should_panic(expected = "outside of task::scope")]
+    fn get_task_outside_scope() {
+        unsafe { Pool::new(dummy()).get_task(); }
+    }
+
+    #[test]
+    fn try_new_err() {
+        let create: TryCreateFn<Dummy, &str> = Box::new(|| Err("oops"));
+        assert_eq!("oops", Pool::try_new(create).unwrap_err());
+    }
+
+    #[test]
+    fn try_get_retries() {
+        // This tests that a failed creation on the slow path isn't cached,
+        // so that a subsequent `try_get` tries again.
+        let count = AtomicUsize::new(0);
+        let create: TryCreateFn<Dummy, &str> = Box::new(move || {
+            match count.fetch_add(1, SeqCst) {
+                1 => Err("oops"),
+                n => Ok(Dummy(n)),
+            }
+        });
+        let pool = Arc::new(Pool::try_new(create).unwrap());
+        assert_eq!(Ok(&Dummy(0)), pool.try_get());
 
         let pool2 = pool.clone();
         thread::spawn(move || {
-            assert_eq!(&Dummy(1), &*pool2.get());
+            assert_eq!(Err("oops"), pool2.try_get());
+            assert_eq!(Ok(&Dummy(2)), pool2.try_get());
+            assert_eq!(Ok(&Dummy(2)), pool2.try_get());
         }).join().unwrap();
+        assert_eq!(Ok(&Dummy(0)), pool.try_get());
+    }
+
+    #[test]
+    #[cfg_attr(miri, ignore)] // leaks the pool
+    fn static_ref() {
+        let pool: &'static Pool<Dummy> =
+            Box::leak(Box::new(Pool::new(dummy())));
+        assert_eq!(&Dummy(0), pool.get());
+        let handles: Vec<_> = (0..4).map(|_| {
+            thread::spawn(move || pool.get().0)
+        }).collect();
+        let mut ids: Vec<usize> =
+            handles.into_iter().map(|h| h.join().unwrap()).collect();
+        ids.sort();
+        ids.dedup();
+        assert!(!ids.contains(&0));
+        assert_eq!(&Dummy(0), pool.get());
     }
 
     #[test]
@@ -175,5 +4942,6 @@ mod tests {
         fn foo<T: Sync>() {}
         foo::<Pool<String>>();
         foo::<Pool<RefCell<String>>>();
+        foo::<Pool<String, fn() -> String>>();
     }
 }
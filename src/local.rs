@@ -0,0 +1,94 @@
+/*!
+A pool for values that never leave the thread that created them.
+
+A `Pool` requires `T: Send`, since every value is dropped by whichever thread
+drops the pool, and the owner's value may be taken over by another thread. A
+`LocalPool` has no such requirement: it holds a single value, which is created
+lazily by the thread that created the pool, and is only ever handed out to
+that thread. So it needs no atomics or locks, and it can hold
+values like `Rc` that aren't `Send`.
+
+A `LocalPool` whose value isn't `Send` can't be sent to another thread in the
+first place. If the value is `Send`, then the pool can be, but `get` panics
+when it's called from any thread other than the one that created the pool.
+
+```rust
+use std::rc::Rc;
+
+use mempool::local::LocalPool;
+
+let pool = LocalPool::new(Box::new(|| Rc::new(vec![0u8; 16])));
+let buf = pool.get().clone();
+assert!(Rc::ptr_eq(&buf, pool.get()));
+```
+*/
+
+use core::cell::OnceCell;
+use core::fmt;
+
+use thread_id;
+
+/// The type of the initialization function of a `LocalPool`.
+///
+/// Unlike `CreateFn`, it need not be `Send` or `Sync`.
+pub type LocalCreateFn<T> = Box<dyn Fn() -> T + 'static>;
+
+/// A pool holding a single value for the thread that created it.
+///
+/// See the module documentation for details.
+pub struct LocalPool<T, F = LocalCreateFn<T>> {
+    create: F,
+    thread_id: usize,
+    val: OnceCell<T>,
+}
+
+impl<T> LocalPool<T> {
+    /// Create a new pool for the current thread.
+    ///
+    /// `create` is called the first time `get` is called.
+    pub fn new(create: LocalCreateFn<T>) -> LocalPool<T> {
+        LocalPool::with_fn(create)
+    }
+}
+
+impl<T, F: Fn() -> T> LocalPool<T, F> {
+    /// Create a new pool for the current thread with any initialization
+    /// function.
+    ///
+    /// Like `Pool::with_fn`, this avoids boxing the closure.
+    pub fn with_fn(create: F) -> LocalPool<T, F> {
+        LocalPool {
+            create,
+            thread_id: thread_id::current(),
+            val: OnceCell::new(),
+        }
+    }
+
+    /// Get a reference to the value of this pool, creating it if necessary.
+    ///
+    /// # Panics
+    ///
+    /// This panics if it isn't called from the thread that created the pool,
+    /// or if the initialization function calls `get` on the same pool.
+    #[inline(always)]
+    pub fn get(&self) -> &T {
+        assert!(
+            thread_id::current() == self.thread_id,
+            "LocalPool::get called from another thread",
+        );
+        self.val.get_or_init(&self.create)
+    }
+
+    /// Consume the pool and return its value, if it has been created.
+    pub fn into_inner(self) -> Option<T> {
+        self.val.into_inner()
+    }
+}
+
+impl<T, F> fmt::Debug for LocalPool<T, F> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LocalPool")
+            .field("created", &self.val.get().is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,92 @@
+// Models of the pool's synchronization, checked by loom.
+//
+// Run them with `cargo test --release --features loom --lib model`. `--lib`
+// skips the doctests, which use the pool outside of `loom::model`, where
+// loom's primitives panic.
+
+use loom;
+use loom::sync::Arc;
+
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::SeqCst;
+
+use Pool;
+
+fn counter() -> Pool<usize, impl Fn() -> usize + Send + Sync> {
+    let count = AtomicUsize::new(0);
+    Pool::with_fn(move || count.fetch_add(1, SeqCst))
+}
+
+#[test]
+fn racing_owners() {
+    // Two threads race to become the owner. Exactly one of them must get the
+    // owner's value, and the other must get a value of its own.
+    loom::model(|| {
+        let pool = Arc::new(counter());
+        let pool2 = pool.clone();
+        let t = loom::thread::spawn(move || *pool2.get());
+        let a = *pool.get();
+        let b = t.join().unwrap();
+        assert_ne!(a, b);
+        assert!(a == 0 || b == 0);
+        assert_eq!(2, pool.len());
+    });
+}
+
+#[test]
+fn racing_lazy_owners() {
+    // Like `racing_owners`, but the owner's value is created by whichever
+    // thread claims the slot, which must publish it to `peek_owner`.
+    loom::model(|| {
+        let count = AtomicUsize::new(0);
+        let pool = Arc::new(Pool::new_lazy(Box::new(move || {
+            count.fetch_add(1, SeqCst)
+        })));
+        let pool2 = pool.clone();
+        let t = loom::thread::spawn(move || *pool2.get());
+        let a = *pool.get();
+        let b = t.join().unwrap();
+        assert_ne!(a, b);
+        let owner = *pool.peek_owner().unwrap();
+        assert!(owner == a || owner == b);
+    });
+}
+
+#[test]
+fn racing_inserts() {
+    // The owner is settled up front, so both threads insert into the map,
+    // possibly into the same shard. Each must get a distinct value, which
+    // must still be there afterwards.
+    loom::model(|| {
+        let pool = Arc::new(counter());
+        unsafe { pool.get_with_id(1) };
+        let pool2 = pool.clone();
+        let t = loom::thread::spawn(move || unsafe {
+            *pool2.get_with_id(2 + super::SHARDS)
+        });
+        let a = unsafe { *pool.get_with_id(2) };
+        let b = t.join().unwrap();
+        assert_ne!(a, b);
+        assert_eq!(a, unsafe { *pool.get_with_id(2) });
+        assert_eq!(3, pool.len());
+    });
+}
+
+#[test]
+fn racing_bump_generation() {
+    // A thread getting its value races with a bump of the generation. It
+    // must get either its old value or a new one, never a torn one, and its
+    // next call after the bump must get a new one.
+    loom::model(|| {
+        let pool = Arc::new(counter());
+        unsafe { pool.get_with_id(1) };
+        let old = unsafe { *pool.get_with_id(2) };
+        let pool2 = pool.clone();
+        let t = loom::thread::spawn(move || pool2.bump_generation());
+        let racing = unsafe { *pool.get_with_id(2) };
+        t.join().unwrap();
+        let after = unsafe { *pool.get_with_id(2) };
+        assert!(racing == old || racing == after);
+        assert_ne!(old, after);
+    });
+}
@@ -0,0 +1,88 @@
+// With the `numa` feature, each thread is given an id whose shard is one of
+// the shards reserved for the NUMA node the thread is running on when it gets
+// its id. The shards are split between the nodes round robin, so with two
+// nodes, threads on node 0 use the even shards and threads on node 1 use the
+// odd ones. A value is always found in the shard given by its thread's id, so
+// nothing else about the pool changes.
+//
+// The locks of a shard are then only ever taken by threads on one node, so
+// their cache lines stay in that node's caches, and the chunks of a shard are
+// allocated by threads on that node, so they're usually local to it (under
+// the default first touch policy).
+//
+// A thread's node is only read once. If the scheduler migrates it to another
+// node later, then it keeps its id and its values. And if the nodes can't be
+// determined, e.g., on platforms other than Linux, then there is one node,
+// which owns every shard.
+
+use std::fs;
+use std::sync::OnceLock;
+
+use SHARDS;
+
+/// Returns the node the current thread is running on and the number of
+/// nodes that the shards are split between.
+///
+/// The node is always less than the number of nodes. If there are more nodes
+/// than shards, then some nodes share shards.
+pub(crate) fn current() -> (usize, usize) {
+    let nodes = nodes();
+    if nodes <= 1 {
+        return (0, 1);
+    }
+    match node() {
+        Some(node) => (node % nodes, nodes),
+        None => (0, 1),
+    }
+}
+
+/// Returns the number of nodes that the shards are split between.
+pub(crate) fn nodes() -> usize {
+    count().min(SHARDS)
+}
+
+/// Returns true if `id` belongs to one of the shards of the given node.
+pub(crate) fn is_local(id: usize, (node, nodes): (usize, usize)) -> bool {
+    (id % SHARDS) % nodes == node
+}
+
+/// Returns the number of nodes on this machine, or `1` if unknown.
+fn count() -> usize {
+    static NODES: OnceLock<usize> = OnceLock::new();
+    *NODES.get_or_init(|| {
+        fs::read_to_string("/sys/devices/system/node/possible")
+            .ok()
+            .and_then(|list| max_node(list.trim()))
+            .map_or(1, |max| max + 1)
+    })
+}
+
+/// Parses a list of nodes, such as `0-3` or `0,2-3`, and returns the largest.
+pub(crate) fn max_node(list: &str) -> Option<usize> {
+    let mut max = None;
+    for range in list.split(',') {
+        let last = range.rsplit('-').next()?;
+        max = max.max(Some(last.parse().ok()?));
+    }
+    max
+}
+
+#[cfg(target_os = "linux")]
+fn node() -> Option<usize> {
+    let mut cpu: libc::c_uint = 0;
+    let mut node: libc::c_uint = 0;
+    let ret = unsafe {
+        libc::syscall(
+            libc::SYS_getcpu,
+            &mut cpu as *mut libc::c_uint,
+            &mut node as *mut libc::c_uint,
+            std::ptr::null_mut::<libc::c_void>(),
+        )
+    };
+    if ret == 0 { Some(node as usize) } else { None }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn node() -> Option<usize> {
+    None
+}
@@ -0,0 +1,35 @@
+// A thin wrapper around `parking_lot::Mutex`, used in place of
+// `std::sync::Mutex` when the `parking_lot` feature is enabled.
+//
+// Its API mirrors the subset of `std::sync::Mutex` used by this crate, so that
+// the call sites don't depend on which lock is in use. Like the spin lock,
+// it can never be poisoned, so the results of `lock` and `get_mut` are always
+// `Ok`.
+
+use std::convert::Infallible;
+
+use parking_lot::{self, MutexGuard};
+
+pub struct Mutex<T>(parking_lot::Mutex<T>);
+
+/// The error returned by `try_lock` when the lock is held.
+#[derive(Debug)]
+pub struct WouldBlock;
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex(parking_lot::const_mutex(data))
+    }
+
+    pub fn lock(&self) -> Result<MutexGuard<'_, T>, Infallible> {
+        Ok(self.0.lock())
+    }
+
+    pub fn try_lock(&self) -> Result<MutexGuard<'_, T>, WouldBlock> {
+        self.0.try_lock().ok_or(WouldBlock)
+    }
+
+    pub fn get_mut(&mut self) -> Result<&mut T, Infallible> {
+        Ok(self.0.get_mut())
+    }
+}
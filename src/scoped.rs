@@ -0,0 +1,45 @@
+/*!
+Pools whose initialization function borrows from its environment.
+
+A `Pool` never requires its initialization function to be `'static`, so a
+pool may borrow from the stack frame that creates it. `ScopedPool` is the
+boxed flavor of that: its creator is a boxed closure bounded by the lifetime
+`'a`, rather than by `'static` like `CreateFn`.
+
+Every value in a pool, including the values of threads that have exited, is
+only ever dropped when the pool itself is dropped. Since the borrow checker
+won't let a `ScopedPool<'a, T>` outlive `'a`, every value is dropped before
+the data borrowed by the initialization function goes away. Scoped threads
+(see `std::thread::scope`) can share the pool by reference.
+
+```rust
+# #[cfg(feature = "std")] {
+use mempool::scoped::ScopedPool;
+
+struct Config { capacity: usize }
+
+let config = Config { capacity: 4096 };
+let pool: ScopedPool<Vec<u8>> =
+    ScopedPool::new(Box::new(|| Vec::with_capacity(config.capacity)));
+std::thread::scope(|s| {
+    s.spawn(|| assert!(pool.get().capacity() >= 4096));
+});
+assert!(pool.get().capacity() >= 4096);
+# }
+```
+*/
+
+use alloc::boxed::Box;
+
+use Pool;
+
+/// The type of an initialization function that borrows data for `'a`.
+///
+/// `CreateFn<T>` is `ScopedCreateFn<'static, T>`.
+pub type ScopedCreateFn<'a, T> = Box<dyn Fn() -> T + Send + Sync + 'a>;
+
+/// A pool whose initialization function borrows data for `'a`.
+///
+/// It is built with `Pool::new`, just like a pool with a `'static`
+/// initialization function.
+pub type ScopedPool<'a, T> = Pool<T, ScopedCreateFn<'a, T>>;
@@ -0,0 +1,254 @@
+// A shard holds the values of some of the threads other than the owner.
+//
+// References to these values are handed out while other threads keep adding
+// their own values to the same shard, so a value must never move once it has
+// been added. Values are stored in chunks whose capacity is fixed when they
+// are allocated, and a chunk is never filled past its capacity, so it never
+// reallocates. Each chunk is twice as big as the one before it, so adding `n`
+// values takes `O(log n)` allocations, instead of one allocation per value.
+//
+// The map from thread ids to values only stores indices into the chunks, so
+// it is free to move its entries around when it grows. Each index is stored
+// along with the generation of the pool the value was created in. A thread
+// whose value is from an older generation gets a new one, but the old value
+// stays in its chunk, since references to it may still be alive.
+//
+// A value can also be removed, which moves it out of its slot. The slot is
+// then vacant, and is reused by the next value added to the shard. Since a
+// slot may be vacant, slots are `MaybeUninit`, and the shard drops the values
+// in the slots that aren't vacant itself.
+
+use alloc::vec::{self, Vec};
+use core::cell::UnsafeCell;
+use core::mem::{self, MaybeUninit};
+use core::ptr;
+
+use {Map, new_map};
+
+/// The capacity of the first chunk.
+const FIRST_CHUNK: usize = 4;
+
+pub(crate) struct Shard<T> {
+    ids: Map<(usize, usize)>,
+    chunks: Vec<Vec<UnsafeCell<MaybeUninit<T>>>>,
+    vacant: Vec<usize>,
+}
+
+impl<T> Shard<T> {
+    pub(crate) const fn new() -> Shard<T> {
+        Shard { ids: new_map(), chunks: Vec::new(), vacant: Vec::new() }
+    }
+
+    /// Returns the number of threads with a value in this shard.
+    pub(crate) fn len(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// Returns true if the given thread has a value in this shard, from any
+    /// generation.
+    pub(crate) fn contains(&self, thread_id: usize) -> bool {
+        self.ids.contains_key(&thread_id)
+    }
+
+    /// Returns the ids of the threads with a value in this shard.
+    pub(crate) fn thread_ids(&self) -> impl Iterator<Item = usize> + '_ {
+        self.ids.keys().copied()
+    }
+
+    /// Returns a pointer to the value of the given thread, if it has one
+    /// from the given generation.
+    ///
+    /// The pointer remains valid until the value is removed, or the shard is
+    /// cleared or dropped.
+    pub(crate) fn get(&self, thread_id: usize, gen: usize) -> Option<*mut T> {
+        match self.ids.get(&thread_id) {
+            Some(&(index, g)) if g == gen => Some(self.at(index)),
+            _ => None,
+        }
+    }
+
+    /// Adds a value from the given generation for the given thread and
+    /// returns a pointer to it.
+    ///
+    /// If the thread already has a value from that generation, then it is
+    /// kept, since it may have been handed out already, and `value` is given
+    /// back to the caller.
+    pub(crate) fn insert(
+        &mut self,
+        thread_id: usize,
+        gen: usize,
+        value: T,
+    ) -> (*mut T, Option<T>) {
+        if let Some(val) = self.get(thread_id, gen) {
+            return (val, Some(value));
+        }
+        let index = match self.vacant.pop() {
+            Some(index) => {
+                unsafe { self.at(index).write(value) };
+                index
+            }
+            None => {
+                let index = self.slots();
+                let (chunk, _) = locate(index);
+                if chunk == self.chunks.len() {
+                    self.chunks.push(Vec::with_capacity(FIRST_CHUNK << chunk));
+                }
+                let slot = UnsafeCell::new(MaybeUninit::new(value));
+                self.chunks[chunk].push(slot);
+                index
+            }
+        };
+        self.ids.insert(thread_id, (index, gen));
+        (self.at(index), None)
+    }
+
+    /// Removes the value of the given thread from the given generation, if
+    /// it has one, and returns it.
+    ///
+    /// The caller must ensure that no reference to the value is alive.
+    #[cfg(feature = "std")]
+    pub(crate) unsafe fn remove(
+        &mut self,
+        thread_id: usize,
+        gen: usize,
+    ) -> Option<T> {
+        match self.ids.get(&thread_id) {
+            Some(&(index, g)) if g == gen => {
+                self.ids.remove(&thread_id);
+                self.vacant.push(index);
+                Some(self.at(index).read())
+            }
+            _ => None,
+        }
+    }
+
+    fn at(&self, index: usize) -> *mut T {
+        let (chunk, offset) = locate(index);
+        self.chunks[chunk][offset].get().cast()
+    }
+
+    /// Returns the number of slots, including vacant ones.
+    fn slots(&self) -> usize {
+        self.chunks.iter().map(Vec::len).sum()
+    }
+
+    /// Returns the indices of the slots that hold a value.
+    fn live(&self) -> Vec<usize> {
+        let mut vacant = self.vacant.clone();
+        vacant.sort_unstable();
+        (0..self.slots())
+            .filter(|i| vacant.binary_search(i).is_err())
+            .collect()
+    }
+
+    /// Drops every value in this shard.
+    ///
+    /// If dropping a value panics, then the remaining values are still
+    /// dropped as the panic unwinds, and the shard is left empty.
+    pub(crate) fn clear(&mut self) {
+        // The shard is emptied before any value is dropped, so that no value
+        // can be dropped twice.
+        let mut rest = Rest {
+            live: self.live().into_iter(),
+            chunks: mem::take(&mut self.chunks),
+        };
+        self.ids.clear();
+        self.vacant.clear();
+        rest.drop_all();
+    }
+
+    /// Reserves capacity in the map from thread ids to values for at least
+    /// `additional` more threads.
+    #[cfg(feature = "std")]
+    pub(crate) fn reserve(&mut self, additional: usize) {
+        self.ids.reserve(additional);
+    }
+
+    /// Returns the number of threads the map from thread ids to values can
+    /// hold without reallocating.
+    #[cfg(feature = "std")]
+    pub(crate) fn capacity(&self) -> usize {
+        self.ids.capacity()
+    }
+
+    /// Releases excess capacity of the map from thread ids to values.
+    ///
+    /// The chunks themselves are left alone: every chunk but the last one is
+    /// full, and the last one must keep its capacity, since values can't move
+    /// once they've been added.
+    pub(crate) fn shrink_to_fit(&mut self) {
+        #[cfg(feature = "std")]
+        self.ids.shrink_to_fit();
+        self.vacant.shrink_to_fit();
+    }
+
+    /// Returns an iterator over every value in this shard, including values
+    /// from older generations.
+    pub(crate) fn values_mut(&mut self) -> impl Iterator<Item = &mut T> {
+        let live = self.live();
+        let shard = &*self;
+        // Each slot is visited once, so the references don't alias.
+        live.into_iter().map(move |index| unsafe { &mut *shard.at(index) })
+    }
+
+    /// Returns an iterator over references to every value in this shard,
+    /// including values from older generations.
+    ///
+    /// Other threads may be using these values, so the caller must only
+    /// read them if `T: Sync`, and only while no thread has mutable access
+    /// to its value.
+    pub(crate) fn values(&self) -> impl Iterator<Item = &T> {
+        self.live().into_iter().map(move |index| unsafe { &*self.at(index) })
+    }
+
+    /// Returns every value in this shard.
+    pub(crate) fn into_values(mut self) -> impl Iterator<Item = T> {
+        let values: Vec<T> = self
+            .live()
+            .into_iter()
+            .map(|index| unsafe { self.at(index).read() })
+            .collect();
+        // Every value has been moved out, so there is nothing left to drop.
+        self.chunks.clear();
+        values.into_iter()
+    }
+}
+
+impl<T> Drop for Shard<T> {
+    fn drop(&mut self) {
+        self.clear();
+    }
+}
+
+/// The values of a cleared shard that haven't been dropped yet.
+///
+/// If dropping one of them panics, then this is dropped as the panic unwinds,
+/// which drops the rest, just like `Vec` does. (If another one panics then,
+/// the process aborts.)
+struct Rest<T> {
+    live: vec::IntoIter<usize>,
+    chunks: Vec<Vec<UnsafeCell<MaybeUninit<T>>>>,
+}
+
+impl<T> Rest<T> {
+    fn drop_all(&mut self) {
+        for index in &mut self.live {
+            let (chunk, offset) = locate(index);
+            let val = self.chunks[chunk][offset].get().cast::<T>();
+            unsafe { ptr::drop_in_place(val) }
+        }
+    }
+}
+
+impl<T> Drop for Rest<T> {
+    fn drop(&mut self) {
+        self.drop_all();
+    }
+}
+
+/// Returns the chunk holding the value at `index`, and its offset into that
+/// chunk.
+fn locate(index: usize) -> (usize, usize) {
+    let chunk = (index / FIRST_CHUNK + 1).ilog2() as usize;
+    (chunk, index - FIRST_CHUNK * ((1 << chunk) - 1))
+}
@@ -0,0 +1,69 @@
+// A minimal spin lock, used in place of `std::sync::Mutex` when the `std`
+// feature is disabled.
+//
+// Its API mirrors the subset of `std::sync::Mutex` used by this crate, except
+// that it can never be poisoned, so its results are always `Ok`.
+
+use core::cell::UnsafeCell;
+use core::convert::Infallible;
+use core::hint;
+use core::ops::{Deref, DerefMut};
+use core::sync::atomic::AtomicBool;
+use core::sync::atomic::Ordering::{Acquire, Relaxed, Release};
+
+pub struct Mutex<T> {
+    locked: AtomicBool,
+    data: UnsafeCell<T>,
+}
+
+unsafe impl<T: Send> Send for Mutex<T> {}
+unsafe impl<T: Send> Sync for Mutex<T> {}
+
+pub struct MutexGuard<'a, T: 'a> {
+    mutex: &'a Mutex<T>,
+}
+
+impl<T> Mutex<T> {
+    pub const fn new(data: T) -> Mutex<T> {
+        Mutex { locked: AtomicBool::new(false), data: UnsafeCell::new(data) }
+    }
+
+    pub fn lock(&self) -> Result<MutexGuard<'_, T>, Infallible> {
+        loop {
+            let acquired = self
+                .locked
+                .compare_exchange_weak(false, true, Acquire, Relaxed)
+                .is_ok();
+            if acquired {
+                return Ok(MutexGuard { mutex: self });
+            }
+            while self.locked.load(Relaxed) {
+                hint::spin_loop();
+            }
+        }
+    }
+
+    pub fn get_mut(&mut self) -> Result<&mut T, Infallible> {
+        Ok(self.data.get_mut())
+    }
+}
+
+impl<'a, T> Deref for MutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> DerefMut for MutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.mutex.data.get() }
+    }
+}
+
+impl<'a, T> Drop for MutexGuard<'a, T> {
+    fn drop(&mut self) {
+        self.mutex.locked.store(false, Release);
+    }
+}
@@ -0,0 +1,113 @@
+/*!
+Per-task values for async code running on [tokio](https://docs.rs/tokio).
+
+Thread locals are the wrong granularity for async code: many tasks run on the
+same thread, so `Pool::get` would hand all of them the same value, and a task
+may move to another thread whenever it yields. Instead, a future run with
+`scope` is assigned a task id, and `Pool::get_task` uses that id to give each
+task its own value, no matter which thread polls it.
+
+Task ids are reused once the future returned by `scope` completes or is
+dropped, so that its values are handed to a new task rather than kept forever.
+That's why `get_task` is unsafe: the borrow checker only ties the returned
+reference to the pool, so it can't stop the reference from outliving the
+scope, at which point a new task may be handed the same value.
+
+A value returned by `get_task` may be held across an `.await` within the
+scope, since it belongs to the task rather than to the thread polling it. But
+a future that does so is only `Send` (and can only be spawned on a
+multi-threaded runtime) if `T: Sync`.
+
+```rust
+use std::future::poll_fn;
+use std::task::Poll;
+
+use mempool::{Pool, task};
+
+let pool = Pool::with_fn(|| vec![0u8; 1024]);
+let rt = tokio::runtime::Builder::new_current_thread().build().unwrap();
+let len = rt.block_on(task::scope(poll_fn(|_| {
+    // The reference doesn't outlive the scope.
+    Poll::Ready(unsafe { pool.get_task() }.len())
+})));
+assert_eq!(1024, len);
+```
+*/
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::future::Future;
+use std::sync::Mutex;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering::Relaxed;
+
+use Pool;
+
+// Task ids have their top bit set, so they can't collide with thread ids,
+// which are allocated from `1` upwards. Retired task ids are reused smallest
+// first, just like thread ids.
+const TASK_BIT: usize = 1 << (usize::BITS - 1);
+
+static COUNTER: AtomicUsize = AtomicUsize::new(0);
+static RETIRED: Mutex<BinaryHeap<Reverse<usize>>> =
+    Mutex::new(BinaryHeap::new());
+
+tokio::task_local!(static TASK_ID: TaskId);
+
+struct TaskId(usize);
+
+impl TaskId {
+    fn new() -> TaskId {
+        match RETIRED.lock().unwrap().pop() {
+            Some(Reverse(id)) => TaskId(id),
+            None => TaskId(TASK_BIT | (COUNTER.fetch_add(1, Relaxed) + 1)),
+        }
+    }
+}
+
+impl Drop for TaskId {
+    fn drop(&mut self) {
+        RETIRED.lock().unwrap().push(Reverse(self.0));
+    }
+}
+
+/// Run `future` with its own task id, so that `Pool::get_task` gives it its
+/// own values.
+///
+/// Scopes may be nested, in which case the innermost scope's id is used.
+pub fn scope<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    TASK_ID.scope(TaskId::new(), future)
+}
+
+/// Returns the id of the current task, if it is running inside `scope`.
+pub fn current_task_id() -> Option<usize> {
+    TASK_ID.try_with(|id| id.0).ok()
+}
+
+impl<T, F> Pool<T, F> where T: Send, F: Fn() -> T + Send + Sync {
+    /// Get a reference to the current task's value from the pool.
+    ///
+    /// This is like `get`, except the value belongs to the current task
+    /// rather than the current thread. See the `task` module.
+    ///
+    /// # Panics
+    ///
+    /// This panics if it isn't called from within a future run by
+    /// `task::scope`.
+    ///
+    /// # Safety
+    ///
+    /// Once the future returned by the innermost `task::scope` around this
+    /// call completes or is dropped, its task id is reused, and the value is
+    /// handed to the next task. The caller must guarantee that the returned
+    /// reference isn't used after that, e.g., by not letting it escape the
+    /// scoped future.
+    pub unsafe fn get_task(&self) -> &T {
+        let id = current_task_id()
+            .expect("Pool::get_task called outside of task::scope");
+        // A task is only ever polled by one thread at a time, ids of tasks
+        // that are still running are never reused, and the caller guarantees
+        // that the reference doesn't outlive the task.
+        unsafe { self.get_by_id(id) }
+    }
+}
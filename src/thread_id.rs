@@ -0,0 +1,280 @@
+// A counter provides the basis for assigning an id to each thread that tries
+// to access the pool. In particular, the first thread to access a pool becomes
+// its owner, and correspondingly is the only thread with access to the "fast"
+// path.
+//
+// The thread id `0` is a special sentinel value to indicate that the pool has
+// no owner yet. Therefore, all thread ids assigned to a thread start from `1`.
+//
+// Every pool keeps the values it hands to a thread keyed by that thread's id.
+// Those values are never handed to another thread, since the thread local
+// destructors of an exited thread may still be using them (see `Pool`). So
+// the id of a thread that was handed a value by any pool is never given to
+// another thread either.
+//
+// A thread that becomes the owner of a pool also hands the pool a flag that is
+// cleared when the thread exits, which is how `adopt` knows that an owner has
+// exited. The flag is shared by every pool the thread owns, and goes away
+// once the thread has exited and no pool refers to it.
+//
+// The ids of other threads, e.g., threads that only used `checkout`, are
+// retired when they exit and handed to the next thread that needs one. Any
+// values a pool keeps for one of those ids are owned by the pool (such as
+// idle values held for `checkout`), so they may be reused by another thread.
+// Retired ids are handed out smallest first, which keeps the ids in use
+// dense. `COUNTER` is only consulted when there are no retired ids. The
+// retired ids have their own lock, which is only taken when a thread starts
+// or exits, so it never contends with access to a pool.
+//
+// Each thread also caches a pointer to the last value it got from the slow
+// path of a pool, so that a thread that isn't the owner of any pool can skip
+// the lock and the map lookup on subsequent calls. The cache is keyed by a
+// unique id for each pool, which is never reused, so a thread's cache can't
+// refer to a value of a pool that has been dropped (or cleared). It's also
+// keyed by the thread id the value was got for, since a thread that's being
+// torn down may have to use a fresh id (see `current`), in which case its
+// cached value belongs to its old id, which another thread may have taken.
+//
+// A thread may also register callbacks to run when it exits, e.g., to remove
+// its values from a pool. They run before its id is released.
+//
+// With the `numa` feature, ids are also chosen by the NUMA node of the thread
+// that gets one. See the `numa` module for details.
+//
+// With the `debug-tracking` feature, each thread also keeps a list of the
+// pools it has outstanding `TrackedRef`s into, one entry per reference, so
+// that removing its value while one is alive can be caught.
+//
+// This bookkeeping always uses std's synchronization primitives, even under
+// the `loom` feature.
+
+use std::cell::{Cell, OnceCell, RefCell};
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ptr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize};
+use std::sync::atomic::Ordering::{Relaxed, Release};
+
+#[cfg(feature = "numa")]
+use numa;
+
+pub(crate) static COUNTER: AtomicUsize = AtomicUsize::new(0);
+static POOLS: AtomicUsize = AtomicUsize::new(0);
+static RETIRED: Mutex<BinaryHeap<Reverse<usize>>> =
+    Mutex::new(BinaryHeap::new());
+#[cfg(not(feature = "loom"))]
+thread_local!(static THREAD_ID: ThreadId = ThreadId::new());
+#[cfg(feature = "loom")]
+loom::thread_local!(static THREAD_ID: ThreadId = ThreadId::new());
+#[cfg(not(feature = "loom"))]
+thread_local!(static CACHE: Cell<(usize, usize, *mut ())> = const {
+    Cell::new((0, 0, ptr::null_mut()))
+});
+#[cfg(feature = "loom")]
+loom::thread_local!(static CACHE: Cell<(usize, usize, *mut ())> =
+    Cell::new((0, 0, ptr::null_mut())));
+
+/// A flag that is true until a thread exits. See `alive`.
+pub(crate) type Alive = Arc<AtomicBool>;
+
+#[cfg(all(feature = "debug-tracking", not(feature = "loom")))]
+thread_local!(static BORROWS: RefCell<Vec<usize>> = const {
+    RefCell::new(Vec::new())
+});
+#[cfg(all(feature = "debug-tracking", feature = "loom"))]
+loom::thread_local!(static BORROWS: RefCell<Vec<usize>> =
+    RefCell::new(Vec::new()));
+
+/// A callback run with a thread's id when the thread exits.
+pub(crate) type OnExit = Box<dyn FnOnce(usize)>;
+
+struct ThreadId {
+    id: usize,
+    // Whether a pool has handed this thread a value for its id.
+    used: Cell<bool>,
+    // Cleared when this thread exits. See `alive`.
+    alive: OnceCell<Alive>,
+    on_exit: RefCell<Vec<OnExit>>,
+}
+
+impl ThreadId {
+    #[cfg(not(feature = "numa"))]
+    fn new() -> ThreadId {
+        let id = match RETIRED.lock().unwrap().pop() {
+            Some(Reverse(id)) => id,
+            None => fresh(),
+        };
+        ThreadId::with_id(id)
+    }
+
+    /// Takes the smallest retired id that belongs to the current thread's
+    /// node, or a fresh one if there is none.
+    #[cfg(feature = "numa")]
+    fn new() -> ThreadId {
+        let node = numa::current();
+        let local = |id| numa::is_local(id, node);
+        let mut retired = RETIRED.lock().unwrap();
+        let id = match retired.iter().map(|r| r.0).filter(|&r| local(r)).min()
+        {
+            Some(id) => {
+                retired.retain(|r| r.0 != id);
+                id
+            }
+            None => loop {
+                // The fresh ids that belong to other nodes are retired right
+                // away, so that threads on those nodes can take them.
+                let id = fresh();
+                if local(id) {
+                    break id;
+                }
+                retired.push(Reverse(id));
+            },
+        };
+        ThreadId::with_id(id)
+    }
+
+    fn with_id(id: usize) -> ThreadId {
+        ThreadId {
+            id,
+            used: Cell::new(false),
+            alive: OnceCell::new(),
+            on_exit: RefCell::new(Vec::new()),
+        }
+    }
+}
+
+impl Drop for ThreadId {
+    fn drop(&mut self) {
+        for f in self.on_exit.get_mut().drain(..) {
+            f(self.id);
+        }
+        if let Some(alive) = self.alive.get() {
+            alive.store(false, Release);
+        }
+        if !self.used.get() {
+            RETIRED.lock().unwrap().push(Reverse(self.id));
+        }
+    }
+}
+
+/// Records that a pool has handed the current thread a value for `id`, if
+/// that's the current thread's id, so that the id is never given to another
+/// thread.
+pub(crate) fn used(id: usize) {
+    let _ = THREAD_ID.try_with(|t| {
+        if t.id == id {
+            t.used.set(true);
+        }
+    });
+}
+
+/// Returns a flag that is true until the current thread exits, if `id` is
+/// the current thread's id.
+///
+/// The flag is cleared with `Release` ordering, after the thread's exit
+/// callbacks have run, so a thread that reads it as false with `Acquire`
+/// ordering sees everything the exited thread did before that.
+pub(crate) fn alive(id: usize) -> Option<Alive> {
+    THREAD_ID.try_with(|t| {
+        if t.id != id {
+            return None;
+        }
+        let alive = t.alive.get_or_init(|| Arc::new(AtomicBool::new(true)));
+        Some(alive.clone())
+    }).ok().flatten()
+}
+
+fn fresh() -> usize {
+    next(&COUNTER)
+}
+
+/// Increments `counter` and returns its new value.
+///
+/// This panics rather than wrapping around, since the ids it hands out must
+/// never be `0` and must never be handed out twice. With recycled thread
+/// ids, this can only happen after `usize::MAX` concurrent threads, or
+/// `usize::MAX` pools.
+pub(crate) fn next(counter: &AtomicUsize) -> usize {
+    match counter.fetch_update(Relaxed, Relaxed, |n| n.checked_add(1)) {
+        Ok(n) => n + 1,
+        Err(_) => panic!("mempool: ran out of ids"),
+    }
+}
+
+/// Returns the id of the current thread.
+///
+/// If the current thread is being torn down and has already released its id,
+/// which may then have been given to another thread, then a fresh id is
+/// returned instead. (Values created for such an id are never reused by
+/// another thread, but this only happens when a pool is used from a thread
+/// local destructor.)
+#[inline(always)]
+pub(crate) fn current() -> usize {
+    THREAD_ID.try_with(|id| id.id).unwrap_or_else(|_| fresh())
+}
+
+/// Registers `f` to be called with the current thread's id when the thread
+/// exits, before the id is released.
+///
+/// Returns false, without registering `f`, if the current thread is being
+/// torn down.
+pub(crate) fn on_exit(f: OnExit) -> bool {
+    THREAD_ID.try_with(|id| id.on_exit.borrow_mut().push(f)).is_ok()
+}
+
+/// Returns a new id for a pool. Pool ids start from `1` and are never reused.
+pub(crate) fn pool_uid() -> usize {
+    next(&POOLS)
+}
+
+/// Returns the current thread's cached value for the pool with the given id,
+/// if it was cached for the given thread id.
+#[inline(always)]
+pub(crate) fn cached(pool: usize, thread: usize) -> Option<*mut ()> {
+    match CACHE.try_with(Cell::get) {
+        Ok((uid, id, val)) if uid == pool && id == thread => Some(val),
+        _ => None,
+    }
+}
+
+/// Caches the current thread's value for the pool with the given id, got for
+/// the given thread id, replacing the value cached for any other pool.
+pub(crate) fn cache(pool: usize, thread: usize, val: *mut ()) {
+    let _ = CACHE.try_with(|cache| cache.set((pool, thread, val)));
+}
+
+/// Forgets the current thread's cached value if it's the value of the given
+/// pool for the given thread id.
+pub(crate) fn uncache(pool: usize, thread: usize) {
+    if cached(pool, thread).is_some() {
+        cache(0, 0, ptr::null_mut());
+    }
+}
+
+/// Records that the current thread has a new reference into the pool at the
+/// given address.
+#[cfg(feature = "debug-tracking")]
+pub(crate) fn borrow(pool: usize) {
+    let _ = BORROWS.try_with(|borrows| borrows.borrow_mut().push(pool));
+}
+
+/// Records that one of the current thread's references into the pool at the
+/// given address has been dropped.
+#[cfg(feature = "debug-tracking")]
+pub(crate) fn release(pool: usize) {
+    let _ = BORROWS.try_with(|borrows| {
+        let mut borrows = borrows.borrow_mut();
+        if let Some(i) = borrows.iter().rposition(|&p| p == pool) {
+            borrows.swap_remove(i);
+        }
+    });
+}
+
+/// Returns true if the current thread has a reference into the pool at the
+/// given address.
+#[cfg(feature = "debug-tracking")]
+pub(crate) fn borrowed(pool: usize) -> bool {
+    BORROWS.try_with(|borrows| borrows.borrow().contains(&pool))
+        .unwrap_or(false)
+}